@@ -1,35 +1,150 @@
 // use anyhow::{bail, Context};
-use crate::types::{LocationOverride, Overrides};
+use crate::output;
+use crate::preview::MapProvider;
+use crate::types::{CoordinateFormat, LocationOverride, Overrides, OverridesFormat};
 use anyhow::Context;
 use clap::{AppSettings, Clap, ValueHint};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 static LIGHTROOM_DATA: &str = "Adobe/Lightroom/Metadata Presets/";
 static MACDIVE_DATA: &str = "Macdive/MacDive.sqlite";
+static MACDIVE_FILE: &str = "MacDive.sqlite";
 
 #[derive(Clap, Debug)]
 #[clap(author, about, version, name = "MacDive Dive Site Exporter", setting=AppSettings::ColorAuto, setting=AppSettings::ColoredHelp)]
 pub struct Options {
     /// Verbose mode (-v, -vv, -vvv, etc.)
     #[clap(short, long, parse(from_occurrences))]
-    verbose: u8,
+    pub verbose: u8,
     /// Path to the MacDive database file
     #[clap(short, long, parse(from_os_str), value_hint=ValueHint::FilePath)]
     database: Option<PathBuf>,
     /// Path to the Lightroom Settings directory
     #[clap(short, long, parse(from_os_str), value_hint=ValueHint::DirPath)]
     lightroom: Option<PathBuf>,
-    /// Path to the Location overrides file
-    #[clap(short='o', long, parse(from_os_str), value_hint=ValueHint::FilePath)]
-    pub locations: Option<PathBuf>,
+    /// Write presets even if the Lightroom directory does not look like `Metadata Presets`
+    #[clap(long)]
+    lightroom_force: bool,
+    /// Path to a Location overrides file, later files win when given multiple times
+    #[clap(short='o', long, parse(from_os_str), number_of_values = 1, value_hint=ValueHint::FilePath)]
+    pub locations: Vec<PathBuf>,
+    /// Match override names by similarity between 0 and 1 when no name matches exactly
+    #[clap(long, parse(try_from_str = parse_similarity), value_hint=ValueHint::Other)]
+    pub override_name_similarity: Option<f64>,
     /// Google Maps API key for reverse geocoding
     #[clap(short, long, value_hint=ValueHint::Other)]
     pub api_key: Option<String>,
+    /// Skip reverse geocoding even when an API key is given, keeping the stored locations
+    #[clap(long)]
+    pub no_geocode: bool,
+    /// Number of retries with exponential backoff when the Google Maps query limit is exceeded
+    #[clap(long, default_value = "3")]
+    pub max_retries: u32,
+    /// Total number of retries shared by all requests of a run
+    #[clap(long, default_value = "100")]
+    pub retry_budget: usize,
     /// Force export and overwrite all existing files
     #[clap(short, long)]
     pub force: bool,
+    /// Abort on the first failed lookup instead of reporting all failures at the end
+    #[clap(long)]
+    pub fail_fast: bool,
+    /// Exit with code 5 when dive sites were skipped because their lookup failed
+    #[clap(long)]
+    pub strict: bool,
+    /// Look up and store the altitude of dive sites that have none
+    #[clap(long)]
+    pub backfill_altitude: bool,
+    /// Read dive sites back after writing them and fail if the changes did not stick
+    #[clap(long, requires = "backfill-altitude")]
+    pub verify: bool,
+    /// Open Elevation compatible API used to backfill altitudes
+    #[clap(long, default_value = "https://api.open-elevation.com/api/v1/lookup", value_hint=ValueHint::Url)]
+    pub elevation_api: String,
+    /// Disable colored output, also honors the NO_COLOR environment variable
+    #[clap(long)]
+    pub no_color: bool,
+    /// Format of coordinates in the summary table
+    #[clap(long, default_value = "dms", possible_values = &["dd", "ddm", "dms"])]
+    pub coord_format: CoordinateFormat,
+    /// Number of decimals for coordinates in decimal degrees
+    #[clap(long, default_value = "6")]
+    pub coord_precision: u32,
+    /// Also write the summary table to a file
+    #[clap(long, parse(from_os_str), value_hint=ValueHint::FilePath)]
+    pub report: Option<PathBuf>,
+    /// Only write the summary table to the report file instead of printing it
+    #[clap(long, requires = "report")]
+    pub report_only: bool,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Parse a name similarity threshold, which has to be between 0 and 1
+fn parse_similarity(value: &str) -> Result<f64, String> {
+    let similarity = value
+        .parse::<f64>()
+        .map_err(|e| format!("Invalid similarity `{}`: {}", value, e))?;
+    if !(0.0..=1.0).contains(&similarity) {
+        return Err(format!(
+            "Similarity must be between 0 and 1, got `{}`",
+            value
+        ));
+    }
+
+    Ok(similarity)
+}
+
+#[derive(Clap, Debug)]
+pub enum Command {
+    /// Verify the MacDive database is readable and has a compatible schema
+    Check {
+        /// Also fail on columns the exporter does not know about
+        #[clap(long)]
+        strict_schema: bool,
+    },
+    /// Show where the database, Lightroom presets and overrides are looked up
+    Paths,
+    /// Write the geocoded location of all dive sites to an overrides file
+    ExportOverrides {
+        /// Path of the overrides file to write
+        #[clap(parse(from_os_str), value_hint=ValueHint::FilePath)]
+        output: PathBuf,
+    },
+    /// Write all dive sites with GPS coordinates to a TomTom OV2 points of interest file
+    ExportOv2 {
+        /// Path of the OV2 file to write, a directory with `--by-country`
+        #[clap(parse(from_os_str), value_hint=ValueHint::AnyPath)]
+        output: PathBuf,
+        /// Write one OV2 file per country into the output directory
+        #[clap(long)]
+        by_country: bool,
+    },
+    /// Create an anonymized MacDive database with synthetic dive sites
+    SampleDb {
+        /// Path of the database file to create
+        #[clap(parse(from_os_str), value_hint=ValueHint::FilePath)]
+        output: PathBuf,
+        /// Number of dive sites to generate
+        #[clap(long, default_value = "10")]
+        sites: u32,
+    },
+    /// Plot all dive sites on a map and open it in the browser
+    Preview(PreviewOptions),
+}
+
+#[derive(Clap, Debug)]
+pub struct PreviewOptions {
+    /// Path of the generated HTML file, defaults to a temporary file
+    #[clap(short, long, parse(from_os_str), value_hint=ValueHint::FilePath)]
+    pub output: Option<PathBuf>,
+    /// Only write the HTML file without opening it in the browser
+    #[clap(long)]
+    pub no_open: bool,
+    /// Tile server for the map background, `none` shows the markers on a blank canvas
+    #[clap(long, default_value = "osm", possible_values = &["osm", "carto", "opentopomap", "none"])]
+    pub map_provider: MapProvider,
 }
 
 #[derive(Error, Debug)]
@@ -40,6 +155,77 @@ pub enum PathError {
     DataDir,
     #[error("File or directory `{0}` is not accessible")]
     Inaccessible(String),
+    #[error("`{0}` is not a directory")]
+    NotADirectory(String),
+    #[error("Directory `{0}` is not writable")]
+    ReadOnly(String),
+    #[error("`{0}` does not look like a Lightroom Metadata Presets directory, use --lightroom-force to write to it anyway")]
+    NotLightroom(String),
+    #[error("MacDive database not found, searched: {}", display_paths(.0))]
+    DatabaseNotFound(Vec<PathBuf>),
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Locations searched for the MacDive database when no `--database` is given
+///
+/// The data directory honors `$XDG_DATA_HOME` on Linux, where the database is usually a copy
+/// from a Mac, so the current directory is searched as well.
+fn database_candidates() -> Vec<PathBuf> {
+    let mut candidates = dirs::data_dir()
+        .map(|p| p.join(MACDIVE_DATA))
+        .into_iter()
+        .collect::<Vec<_>>();
+    if cfg!(not(target_os = "macos")) {
+        candidates.push(PathBuf::from(MACDIVE_FILE));
+    }
+
+    candidates
+}
+
+fn find_database(candidates: Vec<PathBuf>) -> Result<PathBuf, PathError> {
+    match candidates.iter().find(|path| path.is_file()) {
+        Some(path) => std::fs::canonicalize(path).map_err(PathError::Canonicalize),
+        None => Err(PathError::DatabaseNotFound(candidates)),
+    }
+}
+
+/// Make sure presets only end up in a writable Lightroom `Metadata Presets` directory
+fn validate_lightroom_directory(path: &Path) -> Result<(), PathError> {
+    let display = || path.display().to_string();
+    let metadata = std::fs::metadata(path).map_err(|_e| PathError::Inaccessible(display()))?;
+    if !metadata.is_dir() {
+        return Err(PathError::NotADirectory(display()));
+    }
+    // Permission bits do not account for ACLs or read-only mounts, creating a file does
+    tempfile::tempfile_in(path).map_err(|_e| PathError::ReadOnly(display()))?;
+
+    let is_presets_directory = path
+        .file_name()
+        .map(|name| name == "Metadata Presets")
+        .unwrap_or(false);
+    let has_presets = std::fs::read_dir(path)
+        .map_err(|_e| PathError::Inaccessible(display()))?
+        .filter_map(|entry| entry.ok())
+        .any(|entry| {
+            entry
+                .path()
+                .extension()
+                .map(|extension| extension == "lrtemplate")
+                .unwrap_or(false)
+        });
+
+    if is_presets_directory || has_presets {
+        Ok(())
+    } else {
+        Err(PathError::NotLightroom(display()))
+    }
 }
 
 impl Options {
@@ -62,29 +248,133 @@ impl Options {
     }
 
     pub fn overrides(&self) -> anyhow::Result<Overrides> {
-        match &self.locations {
-            Some(path) => {
+        self.locations
+            .iter()
+            .try_fold(Overrides::default(), |merged, path| {
                 let c = std::fs::read_to_string(path)
                     .with_context(|| format!("Could not read file {}", &path.display()))?;
-                Ok(toml::from_str(&c)?)
-            }
-            None => Ok(Overrides {
-                locations: HashMap::new(),
-            }),
-        }
+                let format = OverridesFormat::from_path(path).unwrap_or_else(|| {
+                    output::warning(format!(
+                        "Unknown overrides file extension for {}, reading it as TOML",
+                        path.display()
+                    ));
+                    OverridesFormat::Toml
+                });
+                let overrides = Overrides::parse(&c, format)
+                    .with_context(|| format!("Could not parse file {}", &path.display()))?;
+
+                Ok(merged.merge(overrides))
+            })
     }
 
-    pub fn location_overrides(&self) -> Vec<LocationOverride> {
+    pub fn location_overrides(&self) -> anyhow::Result<Vec<LocationOverride>> {
         self.overrides()
             .map(|v| v.locations.iter().map(|(_, v)| v.clone()).collect())
-            .unwrap_or_else(|_| Vec::new())
     }
 
     pub fn lightroom_metadata(&self) -> Result<PathBuf, PathError> {
-        self.resolve_path(&self.lightroom, LIGHTROOM_DATA)
+        let path = self.resolve_path(&self.lightroom, LIGHTROOM_DATA)?;
+        if !self.lightroom_force {
+            validate_lightroom_directory(&path)?;
+        }
+
+        Ok(path)
     }
 
     pub fn macdive_database(&self) -> Result<PathBuf, PathError> {
-        self.resolve_path(&self.database, MACDIVE_DATA)
+        match self.database {
+            Some(_) => self.resolve_path(&self.database, MACDIVE_DATA),
+            None => find_database(database_candidates()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Directory `name` inside a temporary directory, which is removed when the guard is dropped
+    fn temp_dir(name: &str) -> (TempDir, PathBuf) {
+        let guard = TempDir::new().unwrap();
+        let path = guard.path().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        (guard, path)
+    }
+
+    #[test]
+    fn test_verify_requires_backfill() {
+        assert!(Options::try_parse_from(["macdive-exporter", "--verify"]).is_err());
+
+        let options =
+            Options::try_parse_from(["macdive-exporter", "--backfill-altitude", "--verify"])
+                .unwrap();
+        assert!(options.verify);
+    }
+
+    #[test]
+    fn test_parse_similarity() {
+        assert_eq!(Ok(0.8), parse_similarity("0.8"));
+        assert_eq!(Ok(1.0), parse_similarity("1"));
+        assert!(parse_similarity("1.5").is_err());
+        assert!(parse_similarity("-0.1").is_err());
+        assert!(parse_similarity("NaN").is_err());
+        assert!(parse_similarity("close").is_err());
+    }
+
+    #[test]
+    fn test_find_database() {
+        let (_guard, path) = temp_dir("Macdive");
+        let missing = path.join("Missing.sqlite");
+        let database = path.join(MACDIVE_FILE);
+        std::fs::write(&database, "").unwrap();
+
+        assert_eq!(
+            std::fs::canonicalize(&database).unwrap(),
+            find_database(vec![missing.clone(), database]).unwrap()
+        );
+        assert!(matches!(
+            find_database(vec![missing.clone()]),
+            Err(PathError::DatabaseNotFound(searched)) if searched == vec![missing]
+        ));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn test_database_candidates_include_current_directory() {
+        assert_eq!(
+            Some(&PathBuf::from(MACDIVE_FILE)),
+            database_candidates().last()
+        );
+    }
+
+    #[test]
+    fn test_lightroom_presets_directory() {
+        let (_guard, path) = temp_dir("Metadata Presets");
+        assert!(validate_lightroom_directory(&path).is_ok());
+    }
+
+    #[test]
+    fn test_lightroom_directory_with_presets() {
+        let (_guard, path) = temp_dir("Presets");
+        assert!(matches!(
+            validate_lightroom_directory(&path),
+            Err(PathError::NotLightroom(_))
+        ));
+
+        std::fs::write(path.join("MacDive-Arch.lrtemplate"), "s = {}").unwrap();
+        assert!(validate_lightroom_directory(&path).is_ok());
+    }
+
+    #[test]
+    fn test_lightroom_directory_is_a_file() {
+        let (_guard, path) = temp_dir("Metadata Presets");
+        let file = path.join("MacDive.sqlite");
+        std::fs::write(&file, "").unwrap();
+
+        assert!(matches!(
+            validate_lightroom_directory(&file),
+            Err(PathError::NotADirectory(_))
+        ));
     }
 }
@@ -0,0 +1,74 @@
+use crate::errors::ElevationError;
+use crate::macdive::models::DiveSite;
+use crate::types::Coordinates;
+
+use serde_derive::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct LookupResponse {
+    results: Vec<LookupResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupResult {
+    elevation: f32,
+}
+
+/// Query an Open Elevation compatible API for the elevation in meters at the given position
+pub async fn lookup_elevation(
+    client: &reqwest::Client,
+    api: &str,
+    coordinates: Coordinates,
+) -> Result<f32, ElevationError> {
+    let response: LookupResponse = client
+        .get(api)
+        .query(&[(
+            "locations",
+            format!("{},{}", coordinates.lat, coordinates.lon),
+        )])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let elevation = response
+        .results
+        .first()
+        .map(|result| result.elevation)
+        .ok_or(ElevationError::MissingResult)?;
+
+    Ok(surface_altitude(elevation))
+}
+
+/// Altitude a dive starts at for an elevation looked up at the site
+///
+/// Elevation models report the depth of the sea floor for coastal and ocean sites, dives there
+/// start at sea level.
+fn surface_altitude(elevation: f32) -> f32 {
+    elevation.max(0.0)
+}
+
+/// Resolve the altitude for a MacDive dive site, returning the site id alongside it
+pub async fn site_altitude(
+    client: &reqwest::Client,
+    api: &str,
+    site: DiveSite,
+) -> Result<(i64, f32), ElevationError> {
+    let coordinates = site.try_coordinates().ok_or(ElevationError::InvalidGps)?;
+
+    Ok((site.id, lookup_elevation(client, api, coordinates).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_surface_altitude() {
+        assert_eq!(12.0, surface_altitude(12.0));
+        assert_eq!(0.0, surface_altitude(0.0));
+        assert_eq!(0.0, surface_altitude(-3.0));
+        assert_eq!(0.0, surface_altitude(-25.0));
+    }
+}
@@ -24,6 +24,8 @@ pub enum ConversionError {
 pub enum GeocodingError {
     #[error("Error talking to Google Maps API")]
     GoogleMaps,
+    #[error("Google Maps API query limit exceeded, slow down or raise the quota")]
+    OverQueryLimit,
     #[error("Missing or invalid latitude")]
     InvalidLatitude,
     #[error("Missing or invalid longitude")]
@@ -32,6 +34,16 @@ pub enum GeocodingError {
     InvalidGps,
 }
 
+#[derive(Error, Debug)]
+pub enum ElevationError {
+    #[error("Error talking to the elevation API: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("The elevation API returned no result")]
+    MissingResult,
+    #[error("Invalid GPS coordinates for dive site")]
+    InvalidGps,
+}
+
 #[derive(Error, Debug)]
 pub enum LightroomTemplateError {
     #[error("Invalid UUID in Lightroom Template")]
@@ -43,3 +55,29 @@ pub enum LightroomTemplateError {
     #[error("Error parsing existing Lightroom Template")]
     Parsing,
 }
+
+#[derive(Error, Debug)]
+pub enum PreviewError {
+    #[error("Error writing the map preview")]
+    IoError(#[from] std::io::Error),
+    #[error("Error rendering the map preview")]
+    Rendering(#[from] askama::Error),
+    #[error("Error opening the map preview in the browser")]
+    Browser(#[source] std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum OverridesError {
+    #[error("Error parsing TOML overrides: {0}")]
+    TomlParsing(#[from] toml::de::Error),
+    #[error("Error writing TOML overrides: {0}")]
+    TomlWriting(#[from] toml::ser::Error),
+    #[error("Error reading or writing JSON overrides: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Error reading or writing YAML overrides: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+#[derive(Error, Debug)]
+#[error("Skipped {0} dive sites that could not be looked up")]
+pub struct SkippedSites(pub usize);
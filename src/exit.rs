@@ -0,0 +1,120 @@
+use crate::arguments::PathError;
+use crate::errors::{ElevationError, GeocodingError, SkippedSites};
+use crate::macdive::{DatabaseError, MacDiveError};
+
+/// Process exit codes for failed runs, so scripts can tell bad arguments from a flaky network
+///
+/// Successful runs exit with 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitCode {
+    Failure = 1,
+    Usage = 2,
+    Database = 3,
+    Network = 4,
+    PartialSuccess = 5,
+}
+
+impl ExitCode {
+    /// Classify an error by the first known error type in its chain
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| {
+                if cause.is::<PathError>() {
+                    Some(ExitCode::Usage)
+                } else if cause.is::<DatabaseError>()
+                    || cause.is::<MacDiveError>()
+                    || cause.is::<sqlx::Error>()
+                {
+                    Some(ExitCode::Database)
+                } else if cause.is::<ElevationError>() || cause.is::<reqwest::Error>() {
+                    Some(ExitCode::Network)
+                } else if cause.is::<SkippedSites>() {
+                    Some(ExitCode::PartialSuccess)
+                } else {
+                    match cause.downcast_ref::<GeocodingError>() {
+                        Some(GeocodingError::GoogleMaps) | Some(GeocodingError::OverQueryLimit) => {
+                            Some(ExitCode::Network)
+                        }
+                        _ => None,
+                    }
+                }
+            })
+            .unwrap_or(ExitCode::Failure)
+    }
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ConversionError;
+    use anyhow::{anyhow, Context};
+
+    fn exit_code(result: anyhow::Result<()>) -> ExitCode {
+        ExitCode::from_error(&result.unwrap_err())
+    }
+
+    #[test]
+    fn test_usage_errors() {
+        assert_eq!(ExitCode::Usage, exit_code(Err(PathError::DataDir.into())));
+    }
+
+    #[test]
+    fn test_database_errors() {
+        assert_eq!(
+            ExitCode::Database,
+            exit_code(Err(DatabaseError::InvalidPath.into()))
+        );
+        assert_eq!(
+            ExitCode::Database,
+            exit_code(Err(
+                MacDiveError::DatabaseError(sqlx::Error::RowNotFound).into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_network_errors() {
+        assert_eq!(
+            ExitCode::Network,
+            exit_code(Err(ElevationError::MissingResult.into()))
+        );
+        assert_eq!(
+            ExitCode::Network,
+            exit_code(
+                Err(GeocodingError::OverQueryLimit).context("Could not geocode dive site `Arch`")
+            )
+        );
+        assert_eq!(
+            ExitCode::Network,
+            exit_code(Err(ConversionError::GeocodingError(
+                GeocodingError::GoogleMaps
+            )
+            .into()))
+        );
+    }
+
+    #[test]
+    fn test_partial_success() {
+        assert_eq!(
+            ExitCode::PartialSuccess,
+            exit_code(Err(SkippedSites(2).into()))
+        );
+    }
+
+    #[test]
+    fn test_other_errors() {
+        assert_eq!(
+            ExitCode::Failure,
+            exit_code(Err(GeocodingError::InvalidGps.into()))
+        );
+        assert_eq!(
+            ExitCode::Failure,
+            exit_code(Err(anyhow!("File sample.sqlite already exists")))
+        );
+    }
+}
@@ -1,29 +1,167 @@
 use crate::errors::GeocodingError;
 use crate::types::{DiveSite, LocationOverride};
 
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use geo::{contains::Contains, Coordinate};
+use google_maps::geocoding::error::Error;
+use google_maps::geocoding::response::{status::Status, Response};
 use google_maps::{ClientSettings, LatLng, PlaceType};
 
-fn find_override(
-    latitude: f32,
-    longitude: f32,
-    overrides: &[LocationOverride],
-) -> Option<&LocationOverride> {
-    overrides.iter().find(|location| {
-        location.polygon().contains(&Coordinate {
-            x: longitude,
-            y: latitude,
+/// Outcome of matching a name based override against the dive sites
+#[derive(Debug)]
+pub struct NameMatch {
+    /// Name given in the override
+    pub name: String,
+    /// Names of all dive sites the override matched
+    pub sites: Vec<String>,
+}
+
+/// Normalize a dive site name for comparison: trimmed, single spaces and lowercase
+fn normalize_name(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Normalized names by raw name, every dive site name is compared against every override
+#[derive(Debug, Default)]
+struct NormalizedNames(HashMap<String, String>);
+
+impl NormalizedNames {
+    fn get(&mut self, name: &str) -> &str {
+        if !self.0.contains_key(name) {
+            self.0.insert(name.to_string(), normalize_name(name));
+        }
+
+        &self.0[name]
+    }
+}
+
+/// Dive sites with the normalized name, or with a minimum similarity between 0 and 1 if a
+/// threshold is given and no name matches exactly
+fn matching_sites<'a>(
+    name: &str,
+    sites: &'a [DiveSite],
+    threshold: Option<f64>,
+    names: &mut NormalizedNames,
+) -> Vec<&'a DiveSite> {
+    let expected = names.get(name).to_string();
+    let exact = sites
+        .iter()
+        .filter(|site| names.get(&site.name) == expected)
+        .collect::<Vec<_>>();
+
+    match threshold {
+        Some(threshold) if exact.is_empty() => sites
+            .iter()
+            .filter(|site| {
+                strsim::normalized_levenshtein(&expected, names.get(&site.name)) >= threshold
+            })
+            .collect(),
+        _ => exact,
+    }
+}
+
+/// Fraction of `uuid` overrides that match one of the dive sites, `None` without any
+pub fn matching_uuid_fraction(overrides: &[LocationOverride], sites: &[DiveSite]) -> Option<f64> {
+    let uuids = sites.iter().map(|site| site.uuid).collect::<HashSet<_>>();
+    let keyed = overrides
+        .iter()
+        .filter_map(|location| location.uuid)
+        .collect::<Vec<_>>();
+    if keyed.is_empty() {
+        return None;
+    }
+
+    let matching = keyed.iter().filter(|uuid| uuids.contains(uuid)).count();
+    Some(matching as f64 / keyed.len() as f64)
+}
+
+/// Turn overrides given by dive site `name` into overrides for the `uuid` of the matching site
+///
+/// An override matching no dive site, or more than one ambiguously, is kept for its `area`
+/// only. The returned overrides are ordered so that explicit
+/// `uuid` overrides win over resolved names.
+pub fn resolve_name_overrides(
+    overrides: Vec<LocationOverride>,
+    sites: &[DiveSite],
+    threshold: Option<f64>,
+) -> (Vec<LocationOverride>, Vec<NameMatch>) {
+    let mut explicit = Vec::new();
+    let mut resolved = Vec::new();
+    let mut matches = Vec::new();
+    let mut names = NormalizedNames::default();
+
+    for location in overrides {
+        let name = match (&location.uuid, &location.name) {
+            (None, Some(name)) => name.clone(),
+            _ => {
+                explicit.push(location);
+                continue;
+            }
+        };
+
+        let matched = matching_sites(&name, sites, threshold, &mut names);
+
+        match matched.as_slice() {
+            [site] => resolved.push(LocationOverride {
+                uuid: Some(site.uuid),
+                area: Vec::new(),
+                ..location
+            }),
+            _ => explicit.push(location),
+        }
+
+        matches.push(NameMatch {
+            name,
+            sites: matched.iter().map(|site| site.name.clone()).collect(),
+        });
+    }
+
+    let (uuids, areas): (Vec<_>, Vec<_>) = explicit
+        .into_iter()
+        .partition(|location| location.uuid.is_some());
+
+    (
+        uuids.into_iter().chain(resolved).chain(areas).collect(),
+        matches,
+    )
+}
+
+/// Find the override for a dive site
+///
+/// Overrides are matched in order of precedence:
+/// 1. the override with the `uuid` of the dive site, explicit ones before resolved `name`s
+/// 2. the first override whose `area` contains the coordinates of the dive site
+fn find_override<'a>(
+    site: &DiveSite,
+    overrides: &'a [LocationOverride],
+) -> Option<&'a LocationOverride> {
+    overrides
+        .iter()
+        .find(|location| location.uuid == Some(site.uuid))
+        .or_else(|| {
+            overrides.iter().find(|location| {
+                !location.area.is_empty()
+                    && location.polygon().contains(&Coordinate {
+                        x: site.longitude,
+                        y: site.latitude,
+                    })
+            })
         })
-    })
 }
 
 pub fn apply_overrides(
     mut site: DiveSite,
     overrides: &[LocationOverride],
 ) -> Result<DiveSite, GeocodingError> {
-    if let Some(loc) = find_override(site.latitude, site.longitude, overrides) {
+    if let Some(loc) = find_override(&site, overrides) {
         if let Some(country) = &loc.country {
             site.country = country.to_owned()
         }
@@ -39,24 +177,17 @@ pub fn apply_overrides(
         if let Some(locality) = &loc.locality {
             site.locality = Some(locality.to_owned())
         }
+        if let Some(body_of_water) = &loc.body_of_water {
+            site.body_of_water = Some(body_of_water.to_owned())
+        }
     }
 
     Ok(site)
 }
-pub async fn geocode_site(site: DiveSite, key: &str) -> Result<DiveSite, GeocodingError> {
-    let client = ClientSettings::new(key);
-    let latlng: LatLng = site.clone().try_into()?;
-
-    let location = client
-        .reverse_geocoding(latlng)
-        // .with_result_type(PlaceType::PlusCode)
-        .with_result_types(&[PlaceType::PlusCode, PlaceType::Country])
-        .execute()
-        .await
-        .map_err(|_e| GeocodingError::GoogleMaps)?;
-
+/// Apply the address components of a reverse geocoding response to a dive site
+pub fn apply_geocoding(site: DiveSite, response: Response) -> DiveSite {
     let mut geocoded_site = DiveSite { ..site };
-    for result in location.results {
+    for result in response.results {
         for component in result.address_components {
             // Country
             if component.types.contains(&PlaceType::Country) {
@@ -89,8 +220,406 @@ pub async fn geocode_site(site: DiveSite, key: &str) -> Result<DiveSite, Geocodi
                 geocoded_site.locality = Some(component.short_name);
                 continue;
             }
+            // Body of water
+            if component.types.contains(&PlaceType::NaturalFeature) {
+                geocoded_site.body_of_water = Some(component.long_name);
+                continue;
+            }
+        }
+    }
+
+    geocoded_site
+}
+
+/// Delay before the first retry, doubled for each following attempt
+static BACKOFF_DELAY: Duration = Duration::from_millis(500);
+
+/// Retries shared by all requests of a run, bounding the total time spent backing off
+#[derive(Debug)]
+pub struct RetryBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl RetryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take a retry from the budget, `false` once it is exhausted
+    pub fn acquire(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                if used < self.limit {
+                    Some(used + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+/// Retry a request with exponential backoff while Google reports the query limit as exceeded
+///
+/// Other errors are returned right away. Once `max_retries` or the shared `budget` is exhausted
+/// the last [`GeocodingError::OverQueryLimit`] is returned so the user knows to slow down.
+pub async fn with_backoff<F, Fut, T>(
+    max_retries: u32,
+    delay: Duration,
+    budget: &RetryBudget,
+    mut request: F,
+) -> Result<T, GeocodingError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, GeocodingError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Err(GeocodingError::OverQueryLimit) if attempt < max_retries && budget.acquire() => {
+                tokio::time::sleep(delay * 2u32.pow(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Reverse geocode a position, `None` if Google has no address for it
+async fn reverse_geocode(
+    client: &ClientSettings,
+    latlng: LatLng,
+) -> Result<Option<Response>, GeocodingError> {
+    let response = client
+        .reverse_geocoding(latlng)
+        .with_result_types(&[
+            PlaceType::PlusCode,
+            PlaceType::Country,
+            PlaceType::NaturalFeature,
+        ])
+        .execute()
+        .await;
+
+    match response {
+        Ok(response) => Ok(Some(response)),
+        // Dive sites far offshore do not resolve to any address, this is not worth a retry
+        Err(Error::GoogleMapsService(Status::ZeroResults, _)) => Ok(None),
+        Err(Error::GoogleMapsService(Status::OverQueryLimit, _)) => {
+            Err(GeocodingError::OverQueryLimit)
         }
+        Err(_e) => Err(GeocodingError::GoogleMaps),
     }
+}
+
+pub async fn geocode_site(
+    site: DiveSite,
+    key: &str,
+    max_retries: u32,
+    budget: &RetryBudget,
+) -> Result<DiveSite, GeocodingError> {
+    let client = ClientSettings::new(key);
+    let latlng: LatLng = site.clone().try_into()?;
 
-    Ok(geocoded_site)
+    let response = with_backoff(max_retries, BACKOFF_DELAY, budget, || {
+        reverse_geocode(&client, latlng.clone())
+    })
+    .await?;
+
+    Ok(match response {
+        Some(response) => apply_geocoding(site, response),
+        None => site,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn dive_site() -> DiveSite {
+        DiveSite::sample("Cathedral Cove", 34.015, -119.369)
+    }
+
+    fn location_override(uuid: Option<Uuid>, locality: &str) -> LocationOverride {
+        LocationOverride {
+            uuid,
+            name: None,
+            area: match uuid {
+                Some(_) => vec![],
+                None => vec![
+                    (-119.4567, 34.0314),
+                    (-119.4567, 33.9935),
+                    (-119.3358, 33.9935),
+                    (-119.3358, 34.0314),
+                ],
+            },
+            country: None,
+            iso_country_code: None,
+            state: None,
+            region: None,
+            locality: Some(locality.to_string()),
+            body_of_water: None,
+        }
+    }
+
+    #[test]
+    fn test_apply_area_override() {
+        let overrides = vec![location_override(None, "Anacapa Island")];
+        let site = apply_overrides(dive_site(), &overrides).unwrap();
+
+        assert_eq!(Some("Anacapa Island".to_string()), site.locality);
+    }
+
+    #[test]
+    fn test_uuid_override_wins_over_area() {
+        let overrides = vec![
+            location_override(None, "Anacapa Island"),
+            location_override(Some(dive_site().uuid), "East Anacapa"),
+        ];
+        let site = apply_overrides(dive_site(), &overrides).unwrap();
+
+        assert_eq!(Some("East Anacapa".to_string()), site.locality);
+    }
+
+    fn name_override(name: &str, locality: &str) -> LocationOverride {
+        LocationOverride {
+            name: Some(name.to_string()),
+            area: vec![],
+            ..location_override(None, locality)
+        }
+    }
+
+    #[test]
+    fn test_uuid_override_for_other_site() {
+        let overrides = vec![location_override(Some(Uuid::nil()), "Elsewhere")];
+        let site = apply_overrides(dive_site(), &overrides).unwrap();
+
+        assert_eq!(None, site.locality);
+    }
+
+    #[test]
+    fn test_matching_uuid_fraction() {
+        let sites = vec![dive_site()];
+
+        assert_eq!(
+            None,
+            matching_uuid_fraction(&[location_override(None, "Anacapa Island")], &sites)
+        );
+        assert_eq!(
+            Some(0.5),
+            matching_uuid_fraction(
+                &[
+                    location_override(Some(dive_site().uuid), "East Anacapa"),
+                    location_override(Some(Uuid::nil()), "Elsewhere"),
+                    name_override("Cathedral Cove", "Anacapa Island"),
+                ],
+                &sites
+            )
+        );
+    }
+
+    #[test]
+    fn test_apply_geocoding() {
+        let response: Response =
+            serde_json::from_str(include_str!("../tests/fixtures/reverse_geocoding.json")).unwrap();
+        let site = apply_geocoding(dive_site(), response);
+
+        assert_eq!("United States", site.country);
+        assert_eq!("US", site.iso_country_code);
+        assert_eq!(Some("California".to_string()), site.state);
+        assert_eq!(Some("Ventura".to_string()), site.region);
+        assert_eq!(Some("Ventura".to_string()), site.locality);
+        assert_eq!(
+            Some("Santa Barbara Channel".to_string()),
+            site.body_of_water
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backoff_retries_over_query_limit() {
+        let mut calls = 0;
+        let result = with_backoff(3, Duration::from_millis(1), &RetryBudget::new(10), || {
+            calls += 1;
+            let result = match calls {
+                1 | 2 => Err(GeocodingError::OverQueryLimit),
+                _ => Ok(calls),
+            };
+            async move { result }
+        })
+        .await;
+
+        assert_eq!(3, result.unwrap());
+        assert_eq!(3, calls);
+    }
+
+    #[tokio::test]
+    async fn test_backoff_gives_up_after_max_retries() {
+        let mut calls = 0;
+        let result: Result<(), _> =
+            with_backoff(2, Duration::from_millis(1), &RetryBudget::new(10), || {
+                calls += 1;
+                async { Err(GeocodingError::OverQueryLimit) }
+            })
+            .await;
+
+        assert!(matches!(result, Err(GeocodingError::OverQueryLimit)));
+        assert_eq!(3, calls);
+    }
+
+    #[tokio::test]
+    async fn test_backoff_stops_when_budget_is_exhausted() {
+        let budget = RetryBudget::new(1);
+        let mut calls = 0;
+        let result: Result<(), _> = with_backoff(3, Duration::from_millis(1), &budget, || {
+            calls += 1;
+            async { Err(GeocodingError::OverQueryLimit) }
+        })
+        .await;
+
+        assert!(matches!(result, Err(GeocodingError::OverQueryLimit)));
+        assert_eq!(2, calls);
+        assert_eq!(1, budget.used());
+        assert!(!budget.acquire());
+    }
+
+    #[tokio::test]
+    async fn test_backoff_does_not_retry_other_results() {
+        let mut calls = 0;
+        let result: Result<Option<()>, _> =
+            with_backoff(3, Duration::from_millis(1), &RetryBudget::new(10), || {
+                calls += 1;
+                // ZERO_RESULTS is reported as an empty response
+                async { Ok(None) }
+            })
+            .await;
+        assert!(result.unwrap().is_none());
+        assert_eq!(1, calls);
+
+        let mut calls = 0;
+        let result: Result<(), _> =
+            with_backoff(3, Duration::from_millis(1), &RetryBudget::new(10), || {
+                calls += 1;
+                async { Err(GeocodingError::GoogleMaps) }
+            })
+            .await;
+        assert!(matches!(result, Err(GeocodingError::GoogleMaps)));
+        assert_eq!(1, calls);
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!("cathedral cove", normalize_name("  Cathedral \t  COVE "));
+    }
+
+    #[test]
+    fn test_normalized_names() {
+        let mut names = NormalizedNames::default();
+
+        assert_eq!("cathedral cove", names.get("Cathedral  Cove"));
+        assert_eq!("cathedral cove", names.get("Cathedral  Cove"));
+        assert_eq!("cathedral cove", names.get("cathedral cove"));
+        assert_eq!(2, names.0.len());
+    }
+
+    #[test]
+    fn test_name_override() {
+        let o = name_override("  cathedral   COVE ", "East Anacapa");
+        let (overrides, matches) = resolve_name_overrides(vec![o], &[dive_site()], None);
+        let site = apply_overrides(dive_site(), &overrides).unwrap();
+
+        assert_eq!(Some("East Anacapa".to_string()), site.locality);
+        assert_eq!(vec!["Cathedral Cove".to_string()], matches[0].sites);
+    }
+
+    #[test]
+    fn test_fuzzy_name_override() {
+        let o = name_override("Cathedral Cave", "East Anacapa");
+
+        let (overrides, _) = resolve_name_overrides(vec![o.clone()], &[dive_site()], None);
+        let site = apply_overrides(dive_site(), &overrides).unwrap();
+        assert_eq!(None, site.locality);
+
+        let (overrides, _) = resolve_name_overrides(vec![o], &[dive_site()], Some(0.8));
+        let site = apply_overrides(dive_site(), &overrides).unwrap();
+        assert_eq!(Some("East Anacapa".to_string()), site.locality);
+    }
+
+    #[test]
+    fn test_ambiguous_name_override() {
+        let o = name_override("Cathedral Cove", "East Anacapa");
+        let other = DiveSite {
+            uuid: Uuid::nil(),
+            ..dive_site()
+        };
+
+        let (overrides, matches) = resolve_name_overrides(vec![o], &[dive_site(), other], None);
+        let site = apply_overrides(dive_site(), &overrides).unwrap();
+
+        assert!(overrides.iter().all(|location| location.uuid.is_none()));
+        assert_eq!(2, matches[0].sites.len());
+        assert_eq!(None, site.locality);
+    }
+
+    #[test]
+    fn test_unresolved_name_override_keeps_area() {
+        let o = LocationOverride {
+            name: Some("Cathedral Cove".to_string()),
+            ..location_override(None, "Anacapa Island")
+        };
+        let other = DiveSite {
+            uuid: Uuid::nil(),
+            ..dive_site()
+        };
+
+        // Ambiguous and unmatched names both fall back to the area of the override
+        for sites in &[vec![dive_site(), other], vec![]] {
+            let (overrides, _) = resolve_name_overrides(vec![o.clone()], sites, None);
+            let site = apply_overrides(dive_site(), &overrides).unwrap();
+
+            assert_eq!(Some("Anacapa Island".to_string()), site.locality);
+        }
+    }
+
+    #[test]
+    fn test_exact_name_wins_over_fuzzy() {
+        let o = name_override("Arch Rock", "Anacapa");
+        let arch_rock = DiveSite {
+            name: "Arch Rock".to_string(),
+            ..dive_site()
+        };
+        let arch_rocks = DiveSite {
+            uuid: Uuid::nil(),
+            name: "Arch Rocks".to_string(),
+            ..dive_site()
+        };
+
+        let (overrides, matches) =
+            resolve_name_overrides(vec![o], &[arch_rock.clone(), arch_rocks], Some(0.8));
+
+        assert_eq!(vec!["Arch Rock".to_string()], matches[0].sites);
+        assert_eq!(Some(arch_rock.uuid), overrides[0].uuid);
+    }
+
+    #[test]
+    fn test_uuid_override_wins_over_name() {
+        let by_name = name_override("Cathedral Cove", "By name");
+        let by_uuid = location_override(Some(dive_site().uuid), "By uuid");
+
+        let (overrides, _) = resolve_name_overrides(vec![by_name, by_uuid], &[dive_site()], None);
+        let site = apply_overrides(dive_site(), &overrides).unwrap();
+
+        assert_eq!(Some("By uuid".to_string()), site.locality);
+    }
 }
@@ -49,6 +49,8 @@ mod filters {
 pub struct MetadataPreset {
     pub id: Uuid,
     pub gps: String,
+    pub latitude: f32,
+    pub longitude: f32,
     pub title: String,
     pub location: String,
     pub city: String,
@@ -70,6 +72,8 @@ impl TryFrom<DiveSite> for MetadataPreset {
         Ok(Self {
             id: site.uuid,
             gps: latlng.to_dms()?,
+            latitude: site.latitude,
+            longitude: site.longitude,
             title: format!(
                 "[Location] {region}: {name}",
                 region = &region,
@@ -92,6 +96,8 @@ impl Default for MetadataPreset {
         Self {
             id: Uuid::nil(),
             gps: r#"0°00'00.0"N 0°00'00.0"E"#.to_string(),
+            latitude: 0.0,
+            longitude: 0.0,
             title: "".to_string(),
             city: "".to_string(),
             region: "".to_string(),
@@ -0,0 +1,45 @@
+use super::DatabaseError;
+
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// Location of a MacDive database on disk
+///
+/// Gzip compressed backups are decompressed to a temporary file, which is removed again when
+/// this is dropped. Keep it alive for as long as a connection to the database is open.
+#[derive(Debug)]
+pub enum DatabaseFile {
+    Plain(PathBuf),
+    Decompressed(NamedTempFile),
+}
+
+impl DatabaseFile {
+    pub fn open(path: &Path) -> Result<Self, DatabaseError> {
+        if path.extension().map(|ext| ext != "gz").unwrap_or(true) {
+            return Ok(DatabaseFile::Plain(path.to_path_buf()));
+        }
+
+        let mut decoder = GzDecoder::new(File::open(path)?);
+        let mut file = tempfile::Builder::new()
+            .prefix("macdive-")
+            .suffix(".sqlite")
+            .tempfile()?;
+        std::io::copy(&mut decoder, &mut file)?;
+
+        Ok(DatabaseFile::Decompressed(file))
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            DatabaseFile::Plain(path) => path,
+            DatabaseFile::Decompressed(file) => file.path(),
+        }
+    }
+
+    /// Whether changes would only end up in a temporary copy of the database
+    pub fn is_backup(&self) -> bool {
+        matches!(self, DatabaseFile::Decompressed(_))
+    }
+}
@@ -1,27 +1,85 @@
+mod backup;
 pub(crate) mod models;
+mod sample;
 // mod schema;
 mod types;
+mod writer;
+
+pub use backup::DatabaseFile;
+pub use sample::create_sample_database;
+pub use writer::spawn_altitude_writer;
 
 use std::path::Path;
 use thiserror::Error;
 
-use models::DiveSite;
+use models::{DiveSite, DiveSiteUpdate, NewDiveSite};
 use sqlx::{Pool, Sqlite, SqlitePool};
+use types::NsDate;
+use uuid::Uuid;
+
+pub(crate) type ConnectionPool = Pool<Sqlite>;
+
+/// Columns of `ZDIVESITE` read by the exporter
+static DIVESITE_COLUMNS: &[&str] = &[
+    "Z_PK",
+    "Z_ENT",
+    "Z_OPT",
+    "ZALTITUDE",
+    "ZGPSLAT",
+    "ZGPSLON",
+    "ZMODIFIED",
+    "ZBODYOFWATER",
+    "ZCOUNTRY",
+    "ZDIFFICULTY",
+    "ZDIVELOGUUID",
+    "ZFLAG",
+    "ZIMAGE",
+    "ZLASTDIVELOGIMAGEHASH",
+    "ZLOCATION",
+    "ZNAME",
+    "ZNOTES",
+    "ZUUID",
+    "ZWATERTYPE",
+    "ZZOOM",
+];
+
+#[derive(Debug)]
+pub struct Schema {
+    /// Expected columns that are not present on the `ZDIVESITE` table
+    pub missing_columns: Vec<&'static str>,
+    /// Columns of the `ZDIVESITE` table the exporter does not know about
+    pub unexpected_columns: Vec<String>,
+}
 
-type ConnectionPool = Pool<Sqlite>;
+impl Schema {
+    pub fn is_compatible(&self) -> bool {
+        self.missing_columns.is_empty()
+    }
+
+    /// Whether the `ZDIVESITE` table has exactly the columns the exporter was written for
+    pub fn is_exact(&self) -> bool {
+        self.is_compatible() && self.unexpected_columns.is_empty()
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum DatabaseError {
     #[error("Invalid path to MacDive database")]
     InvalidPath,
+    #[error("Error decompressing MacDive database backup: {0}")]
+    Decompression(#[from] std::io::Error),
     #[error("Error querying MacDive database: `{0}`")]
     Query(#[from] sqlx::Error),
+    #[error(transparent)]
+    Write(#[from] MacDiveError),
 }
 
 #[derive(Error, Debug)]
 pub enum MacDiveError {
     #[error("Error interacting with MacDive database: {0}")]
     DatabaseError(#[from] sqlx::Error),
+    #[error("MacDive database has no Core Data entity `{0}`")]
+    MissingEntity(&'static str),
 }
 
 pub(crate) async fn establish_connection(path: &Path) -> Result<ConnectionPool, DatabaseError> {
@@ -67,3 +125,418 @@ pub async fn sites(connection: &ConnectionPool) -> Result<Vec<DiveSite>, MacDive
 
     Ok(results)
 }
+
+/// Insert a dive site with the Core Data bookkeeping MacDive expects, returning its id
+///
+/// The entity number and the next primary key are taken from `Z_PRIMARYKEY`, which is
+/// updated in the same transaction so Core Data does not hand out the id again.
+pub async fn create_dive_site(
+    site: NewDiveSite,
+    connection: &ConnectionPool,
+) -> Result<i64, MacDiveError> {
+    let mut transaction = connection.begin().await?;
+    let (entity, max): (i64, Option<i64>) =
+        sqlx::query_as("SELECT Z_ENT, Z_MAX FROM Z_PRIMARYKEY WHERE Z_NAME = 'DiveSite'")
+            .fetch_optional(&mut transaction)
+            .await?
+            .ok_or(MacDiveError::MissingEntity("DiveSite"))?;
+    let id = max.unwrap_or(0) + 1;
+
+    sqlx::query(
+        r#"
+        INSERT INTO ZDIVESITE (
+            Z_PK, Z_ENT, Z_OPT, ZALTITUDE, ZGPSLAT, ZGPSLON, ZMODIFIED,
+            ZBODYOFWATER, ZCOUNTRY, ZLOCATION, ZNAME, ZNOTES, ZUUID
+        ) VALUES (?, ?, 1, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(id)
+    .bind(entity)
+    .bind(site.altitude)
+    .bind(site.latitude)
+    .bind(site.longitude)
+    .bind(NsDate::now())
+    .bind(site.body_of_water)
+    .bind(site.country)
+    .bind(site.location)
+    .bind(site.name)
+    .bind(site.notes)
+    .bind(Uuid::new_v4().to_hyphenated().to_string().to_uppercase())
+    .execute(&mut transaction)
+    .await?;
+
+    sqlx::query("UPDATE Z_PRIMARYKEY SET Z_MAX = ? WHERE Z_ENT = ?")
+        .bind(id)
+        .bind(entity)
+        .execute(&mut transaction)
+        .await?;
+    transaction.commit().await?;
+
+    Ok(id)
+}
+
+/// Column value of a dive site update
+enum UpdateValue<'a> {
+    Float(f32),
+    Text(&'a str),
+}
+
+/// Write the set fields of `update` to its dive site and bump the Core Data version
+pub async fn update_dive_site(
+    update: &DiveSiteUpdate,
+    connection: &ConnectionPool,
+) -> Result<(), MacDiveError> {
+    if update.is_empty() {
+        return Ok(());
+    }
+
+    let fields = vec![
+        ("ZALTITUDE", update.altitude.map(UpdateValue::Float)),
+        (
+            "ZBODYOFWATER",
+            update.body_of_water.as_deref().map(UpdateValue::Text),
+        ),
+        ("ZCOUNTRY", update.country.as_deref().map(UpdateValue::Text)),
+        ("ZFLAG", update.flag.as_deref().map(UpdateValue::Text)),
+        (
+            "ZLOCATION",
+            update.location.as_deref().map(UpdateValue::Text),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(column, value)| value.map(|value| (column, value)))
+    .collect::<Vec<_>>();
+
+    let assignments = fields
+        .iter()
+        .map(|(column, _)| format!("{} = ?", column))
+        .collect::<Vec<_>>();
+    let statement = format!(
+        "UPDATE ZDIVESITE SET {}, Z_OPT = COALESCE(Z_OPT, 0) + 1 WHERE Z_PK = ?",
+        assignments.join(", ")
+    );
+
+    let mut query = sqlx::query(&statement);
+    for (_, value) in fields {
+        query = match value {
+            UpdateValue::Float(value) => query.bind(value),
+            UpdateValue::Text(value) => query.bind(value),
+        };
+    }
+    query.bind(update.id).execute(connection).await?;
+
+    Ok(())
+}
+
+/// Read the dive site back and check that every field set in `update` was stored
+pub async fn verify_dive_site(
+    update: &DiveSiteUpdate,
+    connection: &ConnectionPool,
+) -> Result<bool, MacDiveError> {
+    #[derive(sqlx::FromRow)]
+    struct Stored {
+        altitude: Option<f32>,
+        body_of_water: Option<String>,
+        country: Option<String>,
+        flag: Option<String>,
+        location: Option<String>,
+    }
+    fn stored<T: PartialEq>(expected: &Option<T>, actual: &Option<T>) -> bool {
+        expected.is_none() || expected == actual
+    }
+
+    let row: Option<Stored> = sqlx::query_as(
+        r#"
+        SELECT
+            ZALTITUDE AS altitude,
+            ZBODYOFWATER AS body_of_water,
+            ZCOUNTRY AS country,
+            ZFLAG AS flag,
+            ZLOCATION AS location
+        FROM ZDIVESITE
+        WHERE Z_PK = ?
+        "#,
+    )
+    .bind(update.id)
+    .fetch_optional(connection)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(false),
+    };
+
+    Ok(stored(&update.altitude, &row.altitude)
+        && stored(&update.body_of_water, &row.body_of_water)
+        && stored(&update.country, &row.country)
+        && stored(&update.flag, &row.flag)
+        && stored(&update.location, &row.location))
+}
+
+pub async fn detect_schema(connection: &ConnectionPool) -> Result<Schema, MacDiveError> {
+    let columns: Vec<String> =
+        sqlx::query_scalar("SELECT name FROM pragma_table_info('ZDIVESITE')")
+            .fetch_all(connection)
+            .await?;
+
+    let missing_columns = DIVESITE_COLUMNS
+        .iter()
+        .filter(|expected| !columns.iter().any(|column| column == *expected))
+        .copied()
+        .collect();
+    let unexpected_columns = columns
+        .into_iter()
+        .filter(|column| !DIVESITE_COLUMNS.contains(&column.as_str()))
+        .collect();
+
+    Ok(Schema {
+        missing_columns,
+        unexpected_columns,
+    })
+}
+
+pub async fn count_sites(connection: &ConnectionPool) -> Result<i64, MacDiveError> {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM ZDIVESITE")
+        .fetch_one(connection)
+        .await?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+    use uuid::Uuid;
+
+    /// Sample database in a temporary directory, which is removed on drop even if a test fails
+    struct SampleDatabase {
+        connection: ConnectionPool,
+        directory: TempDir,
+    }
+
+    impl SampleDatabase {
+        async fn new(sites: u32) -> Self {
+            let directory = TempDir::new().unwrap();
+            let connection =
+                create_sample_database(&directory.path().join("MacDive.sqlite"), sites)
+                    .await
+                    .unwrap();
+
+            SampleDatabase {
+                connection,
+                directory,
+            }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.directory.path().join("MacDive.sqlite")
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sample_database() {
+        let database = SampleDatabase::new(5).await;
+        let connection = &database.connection;
+
+        assert!(database.path().exists());
+        assert!(detect_schema(connection).await.unwrap().is_exact());
+        assert_eq!(5, count_sites(connection).await.unwrap());
+        let sites = sites(connection).await.unwrap();
+        assert_eq!(5, sites.len());
+        for site in sites {
+            let converted: Result<crate::types::DiveSite, _> = site.try_into();
+            assert!(converted.is_ok(), "{:?}", converted.unwrap_err());
+        }
+    }
+
+    async fn updated_site(update: DiveSiteUpdate) -> (SampleDatabase, DiveSite, DiveSite) {
+        let database = SampleDatabase::new(2).await;
+        let connection = &database.connection;
+        let find = |sites: Vec<DiveSite>| sites.into_iter().find(|site| site.id == 1).unwrap();
+
+        let before = find(sites(connection).await.unwrap());
+        update_dive_site(&DiveSiteUpdate { id: 1, ..update }, connection)
+            .await
+            .unwrap();
+        let after = find(sites(connection).await.unwrap());
+
+        (database, before, after)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_single_field() {
+        let (_, before, after) = updated_site(DiveSiteUpdate {
+            altitude: Some(1250.5),
+            ..DiveSiteUpdate::default()
+        })
+        .await;
+
+        assert_eq!(Some(1250.5), after.altitude);
+        assert_eq!(before.country, after.country);
+        assert_eq!(before.name, after.name);
+        assert_eq!(Some(2), after.opt);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_multiple_fields() {
+        let (_, before, after) = updated_site(DiveSiteUpdate {
+            body_of_water: Some("Caribbean Sea".to_string()),
+            country: Some("Mexico".to_string()),
+            flag: Some("MX".to_string()),
+            location: Some("Cozumel".to_string()),
+            ..DiveSiteUpdate::default()
+        })
+        .await;
+
+        assert_eq!(Some("Caribbean Sea".to_string()), after.body_of_water);
+        assert_eq!(Some("Mexico".to_string()), after.country);
+        assert_eq!(Some("MX".to_string()), after.flag);
+        assert_eq!(Some("Cozumel".to_string()), after.location);
+        assert_eq!(before.altitude, after.altitude);
+        assert_eq!(Some(2), after.opt);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_dive_site() {
+        let update = DiveSiteUpdate {
+            id: 1,
+            altitude: Some(12.5),
+            country: Some("Egypt".to_string()),
+            ..DiveSiteUpdate::default()
+        };
+        let (database, _, _) = updated_site(update.clone()).await;
+        let connection = &database.connection;
+        assert!(verify_dive_site(&update, connection).await.unwrap());
+
+        sqlx::query("UPDATE ZDIVESITE SET ZCOUNTRY = 'Sudan' WHERE Z_PK = 1")
+            .execute(connection)
+            .await
+            .unwrap();
+        assert!(!verify_dive_site(&update, connection).await.unwrap());
+
+        let missing = DiveSiteUpdate { id: 3, ..update };
+        assert!(!verify_dive_site(&missing, connection).await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_nothing() {
+        let (_, before, after) = updated_site(DiveSiteUpdate::default()).await;
+
+        assert_eq!(before.opt, after.opt);
+        assert_eq!(before.country, after.country);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_create_dive_site() {
+        let database = SampleDatabase::new(2).await;
+        let connection = &database.connection;
+
+        let id = create_dive_site(
+            NewDiveSite {
+                name: "Cathedral Cove".to_string(),
+                latitude: 34.015,
+                longitude: -119.369,
+                country: Some("United States".to_string()),
+                ..NewDiveSite::default()
+            },
+            connection,
+        )
+        .await
+        .unwrap();
+        assert_eq!(3, id);
+
+        let sites = sites(connection).await.unwrap();
+        let site = sites.iter().find(|site| site.id == id).unwrap();
+        assert_eq!(Some("Cathedral Cove".to_string()), site.name);
+        assert_eq!(Some(34.015), site.latitude);
+        assert_eq!(Some(1), site.ent);
+        assert_eq!(Some(1), site.opt);
+        assert!(site.modified_at.is_some());
+        let uuid = site.uuid.clone().unwrap();
+        assert_eq!(uuid.to_uppercase(), uuid);
+        assert!(Uuid::parse_str(&uuid.to_lowercase()).is_ok());
+
+        let max: i64 = sqlx::query_scalar("SELECT Z_MAX FROM Z_PRIMARYKEY WHERE Z_ENT = 1")
+            .fetch_one(connection)
+            .await
+            .unwrap();
+        assert_eq!(id, max);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compressed_backup() {
+        let database = SampleDatabase::new(3).await;
+        database.connection.close().await;
+
+        let backup = database.directory.path().join("MacDive.sqlite.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&backup).unwrap(),
+            flate2::Compression::default(),
+        );
+        std::io::copy(
+            &mut std::fs::File::open(database.path()).unwrap(),
+            &mut encoder,
+        )
+        .unwrap();
+        encoder.finish().unwrap();
+
+        let file = DatabaseFile::open(&backup).unwrap();
+        let decompressed = file.path().to_path_buf();
+        assert!(file.is_backup());
+
+        let connection = establish_connection(&decompressed).await.unwrap();
+        assert_eq!(3, count_sites(&connection).await.unwrap());
+        connection.close().await;
+
+        drop(file);
+        assert!(!decompressed.exists());
+        assert!(!DatabaseFile::open(&database.path()).unwrap().is_backup());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unexpected_columns() {
+        let database = SampleDatabase::new(1).await;
+        let connection = &database.connection;
+        sqlx::query("ALTER TABLE ZDIVESITE ADD COLUMN ZCURRENT VARCHAR")
+            .execute(connection)
+            .await
+            .unwrap();
+
+        let schema = detect_schema(connection).await.unwrap();
+        assert!(schema.is_compatible());
+        assert!(!schema.is_exact());
+        assert_eq!(vec!["ZCURRENT".to_string()], schema.unexpected_columns);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_altitude_writer() {
+        let database = SampleDatabase::new(50).await;
+        let connection = &database.connection;
+        let (writer, handle) = spawn_altitude_writer(connection.clone(), true);
+
+        let producers = sites(connection)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|site| {
+                let writer = writer.clone();
+                tokio::spawn(async move { writer.send((site.id, site.id as f32)).await })
+            })
+            .collect::<Vec<_>>();
+        drop(writer);
+        for producer in producers {
+            producer.await.unwrap().unwrap();
+        }
+
+        let summary = handle.await.unwrap().unwrap();
+        assert_eq!(50, summary.updated);
+        assert_eq!(50, summary.verified);
+        assert!(summary.failed.is_empty());
+        for site in sites(connection).await.unwrap() {
+            assert_eq!(Some(site.id as f32), site.altitude);
+            assert_eq!(Some(2), site.opt);
+        }
+    }
+}
@@ -1,6 +1,8 @@
+use crate::errors::ConversionError;
 use crate::macdive::types::NsDate;
+use crate::types::{widen_coordinate, Coordinates};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct DiveSite {
     pub id: i64,
     pub ent: Option<i64>,
@@ -23,3 +25,98 @@ pub struct DiveSite {
     pub water_type: Option<String>,
     pub zoom: Option<String>,
 }
+
+impl DiveSite {
+    /// Position of the dive site, the error tells which of the values is missing
+    pub fn coordinates(&self) -> Result<Coordinates, ConversionError> {
+        let latitude = self.latitude.ok_or(ConversionError::MissingLatitude)?;
+        let longitude = self.longitude.ok_or(ConversionError::MissingLongitude)?;
+
+        Ok(Coordinates {
+            lat: widen_coordinate(latitude),
+            lon: widen_coordinate(longitude),
+        })
+    }
+
+    /// Position of the dive site if both latitude and longitude are set
+    pub fn try_coordinates(&self) -> Option<Coordinates> {
+        self.coordinates().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coordinates() {
+        let site = DiveSite {
+            latitude: Some(20.5),
+            longitude: Some(-87.25),
+            ..DiveSite::default()
+        };
+        let expected = Coordinates {
+            lat: 20.5,
+            lon: -87.25,
+        };
+
+        assert_eq!(expected, site.coordinates().unwrap());
+        assert_eq!(Some(expected), site.try_coordinates());
+    }
+
+    #[test]
+    fn test_missing_coordinates() {
+        let site = DiveSite {
+            latitude: Some(20.5),
+            ..DiveSite::default()
+        };
+
+        assert!(matches!(
+            site.coordinates(),
+            Err(ConversionError::MissingLongitude)
+        ));
+        assert_eq!(None, site.try_coordinates());
+        assert!(matches!(
+            DiveSite::default().coordinates(),
+            Err(ConversionError::MissingLatitude)
+        ));
+    }
+}
+
+/// Fields of a dive site to insert with [`create_dive_site`]
+///
+/// [`create_dive_site`]: crate::macdive::create_dive_site
+#[derive(Debug, Clone, Default)]
+pub struct NewDiveSite {
+    pub name: String,
+    pub latitude: f32,
+    pub longitude: f32,
+    pub altitude: Option<f32>,
+    pub body_of_water: Option<String>,
+    pub country: Option<String>,
+    pub location: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// Changes to a dive site written with [`update_dive_site`], `None` fields are left untouched
+///
+/// [`update_dive_site`]: crate::macdive::update_dive_site
+#[derive(Debug, Clone, Default)]
+pub struct DiveSiteUpdate {
+    pub id: i64,
+    pub altitude: Option<f32>,
+    pub body_of_water: Option<String>,
+    pub country: Option<String>,
+    pub flag: Option<String>,
+    pub location: Option<String>,
+}
+
+impl DiveSiteUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.altitude.is_none()
+            && self.body_of_water.is_none()
+            && self.country.is_none()
+            && self.flag.is_none()
+            && self.location.is_none()
+    }
+}
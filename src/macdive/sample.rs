@@ -0,0 +1,111 @@
+use super::models::NewDiveSite;
+use super::{create_dive_site, ConnectionPool, DatabaseError};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::SqlitePool;
+use std::path::Path;
+
+/// Core Data entity number of dive sites in the sample database
+const DIVESITE_ENTITY: i64 = 1;
+
+/// Subset of the MacDive schema read by the exporter, as created by Core Data
+static SCHEMA: &[&str] = &[
+    r#"
+    CREATE TABLE Z_PRIMARYKEY (
+        Z_ENT INTEGER PRIMARY KEY,
+        Z_NAME VARCHAR,
+        Z_SUPER INTEGER,
+        Z_MAX INTEGER
+    )
+    "#,
+    r#"
+    CREATE TABLE ZDIVESITE (
+        Z_PK INTEGER PRIMARY KEY,
+        Z_ENT INTEGER,
+        Z_OPT INTEGER,
+        ZALTITUDE FLOAT,
+        ZGPSLAT FLOAT,
+        ZGPSLON FLOAT,
+        ZMODIFIED TIMESTAMP,
+        ZBODYOFWATER VARCHAR,
+        ZCOUNTRY VARCHAR,
+        ZDIFFICULTY VARCHAR,
+        ZDIVELOGUUID VARCHAR,
+        ZFLAG VARCHAR,
+        ZIMAGE VARCHAR,
+        ZLASTDIVELOGIMAGEHASH VARCHAR,
+        ZLOCATION VARCHAR,
+        ZNAME VARCHAR,
+        ZNOTES VARCHAR,
+        ZUUID VARCHAR,
+        ZWATERTYPE VARCHAR,
+        ZZOOM VARCHAR
+    )
+    "#,
+];
+
+static ADJECTIVES: &[&str] = &[
+    "Blue", "Coral", "Hidden", "Twin", "Turtle", "Shark", "Sunken", "Manta",
+];
+static FEATURES: &[&str] = &[
+    "Reef", "Wall", "Garden", "Pinnacle", "Canyon", "Wreck", "Bommie", "Cove",
+];
+static COUNTRIES: &[&str] = &[
+    "Mexico",
+    "Indonesia",
+    "Egypt",
+    "Australia",
+    "Philippines",
+    "Honduras",
+];
+
+fn random_sites(count: u32) -> Vec<NewDiveSite> {
+    let mut rng = rand::thread_rng();
+
+    (0..count)
+        .map(|_| NewDiveSite {
+            name: format!(
+                "{} {}",
+                ADJECTIVES.choose(&mut rng).unwrap_or(&"Blue"),
+                FEATURES.choose(&mut rng).unwrap_or(&"Reef")
+            ),
+            country: COUNTRIES
+                .choose(&mut rng)
+                .map(|country| country.to_string()),
+            latitude: rng.gen_range(-60.0..60.0),
+            longitude: rng.gen_range(-180.0..180.0),
+            ..NewDiveSite::default()
+        })
+        .collect()
+}
+
+/// Create a new SQLite database with the MacDive schema and `sites` synthetic dive sites
+///
+/// The names, countries and coordinates are random, so the database can be shared in bug
+/// reports and used as a test fixture without leaking personal dive data.
+pub async fn create_sample_database(
+    path: &Path,
+    sites: u32,
+) -> Result<ConnectionPool, DatabaseError> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true);
+    let connection = SqlitePool::connect_with(options).await?;
+
+    for statement in SCHEMA {
+        sqlx::query(statement).execute(&connection).await?;
+    }
+    sqlx::query("INSERT INTO Z_PRIMARYKEY (Z_ENT, Z_NAME, Z_SUPER, Z_MAX) VALUES (?, ?, 0, 0)")
+        .bind(DIVESITE_ENTITY)
+        .bind("DiveSite")
+        .execute(&connection)
+        .await?;
+
+    for site in random_sites(sites) {
+        create_dive_site(site, &connection).await?;
+    }
+
+    Ok(connection)
+}
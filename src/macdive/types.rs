@@ -1,4 +1,4 @@
-use chrono::{Duration, NaiveDate, NaiveDateTime};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
 use once_cell::sync::Lazy;
 
 /// A representation of a specific point in time that bridges to Date
@@ -15,6 +15,12 @@ pub struct NsDate(f64);
 static NSDATE_EPOCH: Lazy<NaiveDateTime> =
     Lazy::new(|| NaiveDate::from_ymd(2001, 1, 1).and_hms(0, 0, 0));
 
+impl NsDate {
+    pub fn now() -> Self {
+        NsDate((Utc::now().naive_utc() - *NSDATE_EPOCH).num_milliseconds() as f64 / 1000.0)
+    }
+}
+
 impl From<NsDate> for NaiveDateTime {
     fn from(value: NsDate) -> Self {
         if let Ok(duration) = Duration::from_std(std::time::Duration::from_secs_f64(value.0)) {
@@ -0,0 +1,59 @@
+use super::models::DiveSiteUpdate;
+use super::{update_dive_site, verify_dive_site, ConnectionPool, MacDiveError};
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Number of pending writes buffered before producers wait for the writer
+const WRITE_QUEUE: usize = 32;
+
+/// Channel of dive site ids and their new altitude
+pub type AltitudeSender = mpsc::Sender<(i64, f32)>;
+/// Task applying the updates, resolves once every sender has been dropped
+pub type WriterHandle = JoinHandle<Result<WriteSummary, MacDiveError>>;
+
+/// Outcome of the updates applied by the writer
+#[derive(Debug, Default)]
+pub struct WriteSummary {
+    pub updated: usize,
+    /// Updates confirmed by reading the dive site back
+    pub verified: usize,
+    /// Ids of dive sites that did not keep their update
+    pub failed: Vec<i64>,
+}
+
+/// Apply altitude updates from a single task, SQLite only allows one writer at a time
+///
+/// Lookups keep running concurrently and send their results to the returned channel. The
+/// writer finishes once every sender has been dropped. With `verify` every dive site is read
+/// back after its update.
+pub fn spawn_altitude_writer(
+    connection: ConnectionPool,
+    verify: bool,
+) -> (AltitudeSender, WriterHandle) {
+    let (sender, mut receiver) = mpsc::channel(WRITE_QUEUE);
+    let handle = tokio::spawn(async move {
+        let mut summary = WriteSummary::default();
+        while let Some((id, altitude)) = receiver.recv().await {
+            let update = DiveSiteUpdate {
+                id,
+                altitude: Some(altitude),
+                ..DiveSiteUpdate::default()
+            };
+            update_dive_site(&update, &connection).await?;
+            summary.updated += 1;
+
+            if verify {
+                if verify_dive_site(&update, &connection).await? {
+                    summary.verified += 1;
+                } else {
+                    summary.failed.push(id);
+                }
+            }
+        }
+
+        Ok(summary)
+    });
+
+    (sender, handle)
+}
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Clap;
 use indicatif::ProgressBar;
 use prettytable::{Cell, Row, Table};
@@ -6,19 +6,30 @@ use prettytable::{Cell, Row, Table};
 use std::convert::TryInto;
 
 mod arguments;
+mod elevation;
 mod errors;
+mod exit;
 mod geocode;
 mod lightroom;
 mod macdive;
+mod output;
+mod ov2;
+mod preview;
 mod types;
 
-use arguments::Options;
+use arguments::{Command, Options, PathError, PreviewOptions};
 use console::{style, Emoji};
-use errors::ConversionError;
-use futures::StreamExt;
+use errors::{ConversionError, SkippedSites};
+use exit::ExitCode;
+use futures::{FutureExt, Stream, StreamExt, TryStreamExt};
 use lightroom::MetadataPreset;
+use std::path::{Path, PathBuf};
+use types::{
+    format_coordinate, widen_coordinate, Axis, CoordinateFormat, LocationOverride, Overrides,
+    OverridesFormat,
+};
 
-fn print_summary(presets: &[MetadataPreset]) {
+fn summary_table(presets: &[MetadataPreset], format: CoordinateFormat, precision: u32) -> Table {
     let mut table = Table::new();
     table.set_format(*prettytable::format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
     table.set_titles(Row::new(vec![
@@ -37,22 +48,478 @@ fn print_summary(presets: &[MetadataPreset]) {
             Cell::new(&site.region),
             Cell::new(&site.state),
             Cell::new(&site.country),
-            Cell::new(&site.gps),
+            Cell::new(&format!(
+                "{} {}",
+                format_coordinate(
+                    widen_coordinate(site.latitude),
+                    Axis::Latitude,
+                    format,
+                    precision
+                ),
+                format_coordinate(
+                    widen_coordinate(site.longitude),
+                    Axis::Longitude,
+                    format,
+                    precision
+                )
+            )),
         ]));
     }
 
-    table.printstd();
+    table
+}
+
+/// Print the summary table and write it to the `--report` file, which is written even when
+/// there are no presets so it never holds the summary of an earlier run
+fn report_summary(options: &Options, presets: &[MetadataPreset]) -> Result<()> {
+    let table = summary_table(presets, options.coord_format, options.coord_precision);
+    if !options.report_only && !presets.is_empty() {
+        table.printstd();
+    }
+
+    if let Some(path) = &options.report {
+        let mut file = std::fs::File::create(path)
+            .with_context(|| format!("Could not write file {}", path.display()))?;
+        table.print(&mut file)?;
+    }
+
+    Ok(())
 }
 
 static LOOKING_GLASS: Emoji<'_, '_> = Emoji("🔍  ", "");
 static DIVING_MASK: Emoji<'_, '_> = Emoji("🤿️  ", "");
 static SATELLITE: Emoji<'_, '_> = Emoji("🛰️   ", "");
 static FILE_FOLDER: Emoji<'_, '_> = Emoji("📂  ", "");
+static WORLD_MAP: Emoji<'_, '_> = Emoji("🗺️   ", "");
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let options = Options::parse();
+/// Gather the results of concurrent lookups
+///
+/// With `--fail-fast` the first failure aborts the remaining lookups, otherwise all failures
+/// are collected so they can be reported once every lookup finished.
+async fn collect_lookups<T>(
+    lookups: impl Stream<Item = Result<T>>,
+    fail_fast: bool,
+) -> Result<(Vec<T>, Vec<anyhow::Error>)> {
+    if fail_fast {
+        return Ok((lookups.try_collect().await?, Vec::new()));
+    }
+
+    let mut successes = Vec::new();
+    let mut failures = Vec::new();
+    for result in lookups.collect::<Vec<_>>().await {
+        match result {
+            Ok(value) => successes.push(value),
+            Err(e) => failures.push(e),
+        }
+    }
+
+    Ok((successes, failures))
+}
+
+/// Print the failed lookups, with `--strict` skipped dive sites fail the run
+fn report_failures(failures: &[anyhow::Error], strict: bool) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    for failure in failures {
+        output::error(format!("{:#}", failure));
+    }
+    if strict {
+        return Err(SkippedSites(failures.len()).into());
+    }
+    output::warning(SkippedSites(failures.len()));
+
+    Ok(())
+}
+
+fn report_name_matches(matches: &[geocode::NameMatch], verbose: bool) {
+    for name_match in matches {
+        match name_match.sites.as_slice() {
+            [] => output::warning(format!(
+                "Override `{}` does not match any dive site",
+                name_match.name
+            )),
+            [site] if verbose => println!(
+                "Override `{}` matched dive site `{}`",
+                name_match.name, site
+            ),
+            [_] => {}
+            sites => output::warning(format!(
+                "Override `{}` is ambiguous, only applying its area. Matching dive sites: {}",
+                name_match.name,
+                sites.join(", ")
+            )),
+        }
+    }
+}
+
+/// Load the location overrides for the dive sites and warn about ones that do not apply
+fn resolve_overrides(
+    options: &Options,
+    sites: &[types::DiveSite],
+) -> Result<Vec<LocationOverride>> {
+    let overrides = options.location_overrides()?;
+    if let Some(fraction) = geocode::matching_uuid_fraction(&overrides, sites) {
+        if fraction < 0.5 {
+            output::warning(format!(
+                "Only {:.0}% of the uuid overrides match a dive site, is this the right MacDive database?",
+                fraction * 100.0
+            ));
+        }
+    }
+
+    let (overrides, name_matches) =
+        geocode::resolve_name_overrides(overrides, sites, options.override_name_similarity);
+    report_name_matches(&name_matches, options.verbose > 0);
+
+    Ok(overrides)
+}
+
+/// Connect to the MacDive database, the returned file has to outlive the connection
+async fn open_database(
+    options: &Options,
+) -> Result<(macdive::DatabaseFile, macdive::ConnectionPool)> {
+    let database = macdive::DatabaseFile::open(&options.macdive_database()?)?;
+    let connection = macdive::establish_connection(database.path()).await?;
+
+    Ok((database, connection))
+}
+
+async fn fetch_sites(options: &Options) -> Result<Vec<types::DiveSite>> {
+    let (_database, connection) = open_database(options).await?;
+    let sites = macdive::sites(&connection)
+        .await?
+        .into_iter()
+        .map(|site| site.try_into())
+        .collect::<Result<Vec<types::DiveSite>, ConversionError>>()?;
+
+    Ok(sites)
+}
+
+async fn backfill_altitude(options: &Options) -> Result<()> {
+    let (database, connection) = open_database(options).await?;
+    if database.is_backup() {
+        bail!("Altitudes can not be backfilled into a compressed database backup");
+    }
+    let sites = macdive::sites(&connection)
+        .await?
+        .into_iter()
+        .filter(|site| site.altitude.is_none())
+        .collect::<Vec<_>>();
+
+    let client = reqwest::Client::new();
+    let (writer, handle) = macdive::spawn_altitude_writer(connection.clone(), options.verify);
+    let pb = ProgressBar::new(sites.len() as u64);
+    let lookups = futures::stream::iter(sites)
+        .map(|site| {
+            pb.inc(1);
+            let id = site.id;
+            let writer = writer.clone();
+            let client = &client;
+            async move {
+                let update = elevation::site_altitude(client, &options.elevation_api, site)
+                    .await
+                    .with_context(|| {
+                        format!("Could not look up the altitude of dive site {}", id)
+                    })?;
+                writer.send(update).await?;
+
+                Ok::<_, anyhow::Error>(())
+            }
+        })
+        .buffer_unordered(10usize);
+    let lookups = collect_lookups(lookups, options.fail_fast).await;
+    pb.finish_and_clear();
+
+    // Every lookup finished, closing the channel lets the writer apply the remaining updates.
+    // A send only fails once the writer stopped, its database error takes precedence then.
+    drop(writer);
+    let summary = handle.await??;
+    let (_, failures) = lookups?;
+    output::success(format!(
+        "Updated the altitude of {} dive sites",
+        summary.updated
+    ));
+    if options.verify {
+        output::success(format!("Verified {} dive sites", summary.verified));
+    }
+
+    // Print the failed lookups before a verification failure ends the run
+    let reported = report_failures(&failures, options.strict);
+    if !summary.failed.is_empty() {
+        bail!(
+            "{} dive sites did not keep their altitude: {}",
+            summary.failed.len(),
+            summary
+                .failed
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    reported?;
+
+    Ok(())
+}
+
+/// Reverse geocode dive sites and apply the location overrides to them
+async fn locate_sites(
+    options: &Options,
+    sites: Vec<types::DiveSite>,
+    overrides: &[LocationOverride],
+    pb: &ProgressBar,
+) -> Result<(Vec<types::DiveSite>, Vec<anyhow::Error>)> {
+    let budget = geocode::RetryBudget::new(options.retry_budget);
+    let (sites, failures) = match &options.api_key {
+        _ if options.no_geocode => (sites, Vec::new()),
+        Some(key) => {
+            let lookups = futures::stream::iter(sites)
+                .map(|site| {
+                    pb.inc(1);
+                    let name = site.name.clone();
+                    geocode::geocode_site(site, key, options.max_retries, &budget).map(
+                        move |result| {
+                            result
+                                .with_context(|| format!("Could not geocode dive site `{}`", name))
+                        },
+                    )
+                })
+                .buffer_unordered(10usize);
+            collect_lookups(lookups, options.fail_fast).await?
+        }
+        None => {
+            output::warning("No Google Maps API key given, skipping reverse geocoding");
+            (sites, Vec::new())
+        }
+    };
+    if budget.used() > 0 {
+        output::warning(format!(
+            "Google Maps query limit was hit, used {} of {} retries",
+            budget.used(),
+            budget.limit()
+        ));
+    }
+
+    let sites = sites
+        .into_iter()
+        .map(|site| {
+            geocode::apply_overrides(site, overrides).map_err(ConversionError::GeocodingError)
+        })
+        .collect::<Result<Vec<_>, ConversionError>>()?;
+
+    Ok((sites, failures))
+}
+
+async fn export_overrides(options: &Options, path: &Path) -> Result<()> {
+    println!(
+        "{} {}Fetching dive sites from MacDive...",
+        style("[1/3]").bold().dim(),
+        DIVING_MASK
+    );
+    let sites = fetch_sites(options).await?;
 
+    println!(
+        "{} {}Looking up addresses for dive sites...",
+        style("[2/3]").bold().dim(),
+        SATELLITE
+    );
+    let overrides = resolve_overrides(options, &sites)?;
+
+    let pb = ProgressBar::new(sites.len() as u64);
+    let (sites, failures) = locate_sites(options, sites, &overrides, &pb).await?;
+    pb.finish_and_clear();
+
+    println!(
+        "{} {}Writing location overrides to {}...",
+        style("[3/3]").bold().dim(),
+        FILE_FOLDER,
+        path.display()
+    );
+    let snapshot = Overrides::from(sites.as_slice());
+    let format = OverridesFormat::from_path(path).unwrap_or(OverridesFormat::Toml);
+    std::fs::write(path, snapshot.render(format)?)
+        .with_context(|| format!("Could not write file {}", path.display()))?;
+    output::success(format!(
+        "Wrote {} location overrides",
+        snapshot.locations.len()
+    ));
+    report_failures(&failures, options.strict)?;
+
+    Ok(())
+}
+
+async fn export_ov2(options: &Options, path: &Path, by_country: bool) -> Result<()> {
+    let (_database, connection) = open_database(options).await?;
+    // OV2 records need a position, dive sites without GPS coordinates are skipped
+    let sites = macdive::sites(&connection)
+        .await?
+        .into_iter()
+        .filter(|site| site.try_coordinates().is_some())
+        .collect::<Vec<_>>();
+
+    if by_country {
+        let files = ov2::write_ov2_by_country(path, &sites)
+            .with_context(|| format!("Could not write to directory {}", path.display()))?;
+        output::success(format!(
+            "Wrote {} dive sites to {} OV2 files in {}",
+            sites.len(),
+            files,
+            path.display()
+        ));
+    } else {
+        ov2::write_ov2(path, &sites)
+            .with_context(|| format!("Could not write file {}", path.display()))?;
+        output::success(format!(
+            "Wrote {} dive sites to {}",
+            sites.len(),
+            path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+async fn sample_db(path: &Path, sites: u32) -> Result<()> {
+    if path.exists() {
+        bail!("File {} already exists", path.display());
+    }
+
+    macdive::create_sample_database(path, sites).await?;
+    output::success(format!(
+        "Wrote sample database with {} dive sites to {}",
+        sites,
+        path.display()
+    ));
+
+    Ok(())
+}
+
+async fn check(options: &Options, strict_schema: bool) -> Result<()> {
+    let path = options.macdive_database()?;
+    let database = macdive::DatabaseFile::open(&path)?;
+    let connection = macdive::establish_connection(database.path()).await?;
+    let schema = macdive::detect_schema(&connection).await?;
+
+    println!("{} {}", style("Database:").bold(), path.display());
+    if !schema.is_compatible() {
+        bail!(
+            "Incompatible MacDive schema, ZDIVESITE is missing: {}",
+            schema.missing_columns.join(", ")
+        );
+    }
+    if !schema.is_exact() {
+        let message = format!(
+            "Unexpected MacDive schema, ZDIVESITE has unknown columns: {}",
+            schema.unexpected_columns.join(", ")
+        );
+        if strict_schema {
+            bail!(message);
+        }
+        output::warning(message);
+    }
+    output::success("Schema is compatible");
+
+    let total = macdive::count_sites(&connection).await?;
+    let sites = macdive::sites(&connection).await?;
+    println!(
+        "{} {} ({} with GPS coordinates)",
+        style("Dive sites:").bold(),
+        total,
+        sites.len()
+    );
+
+    Ok(())
+}
+
+/// Describe whether a path exists and can be read and written
+fn path_access(path: &Path) -> String {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return "does not exist".to_string(),
+    };
+    // Actually try the access, the permission bits alone miss ACLs and read-only mounts
+    let (readable, writable) = if metadata.is_dir() {
+        (
+            std::fs::read_dir(path).is_ok(),
+            tempfile::tempfile_in(path).is_ok(),
+        )
+    } else {
+        (
+            std::fs::File::open(path).is_ok(),
+            std::fs::OpenOptions::new().write(true).open(path).is_ok(),
+        )
+    };
+
+    format!(
+        "{}, {}",
+        if readable { "readable" } else { "not readable" },
+        if writable { "writable" } else { "read-only" }
+    )
+}
+
+fn print_path(label: &str, path: Result<PathBuf, PathError>) {
+    match path {
+        Ok(path) => println!(
+            "{} {} ({})",
+            style(label).bold(),
+            path.display(),
+            path_access(&path)
+        ),
+        Err(e) => println!("{} {}", style(label).bold(), style(e).red()),
+    }
+}
+
+fn paths(options: &Options) {
+    print_path("Database:", options.macdive_database());
+    print_path("Lightroom presets:", options.lightroom_metadata());
+    if options.locations.is_empty() {
+        println!("{} none given", style("Overrides:").bold());
+    }
+    for path in &options.locations {
+        print_path("Overrides:", Ok(path.clone()));
+    }
+}
+
+async fn preview(options: &Options, preview_options: &PreviewOptions) -> Result<()> {
+    println!(
+        "{} {}Fetching dive sites from MacDive...",
+        style("[1/2]").bold().dim(),
+        DIVING_MASK
+    );
+    let (_database, connection) = open_database(options).await?;
+    let sites = macdive::sites(&connection).await?;
+
+    let path = match &preview_options.output {
+        Some(path) => path.clone(),
+        None => {
+            tempfile::Builder::new()
+                .prefix("macdive-sites-")
+                .suffix(".html")
+                .tempfile()?
+                .keep()?
+                .1
+        }
+    };
+    println!(
+        "{} {}Writing map preview to {}...",
+        style("[2/2]").bold().dim(),
+        WORLD_MAP,
+        path.display()
+    );
+    preview::write_preview(
+        &path,
+        &sites,
+        options.coord_precision,
+        preview_options.map_provider,
+        !preview_options.no_open,
+    )?;
+
+    Ok(())
+}
+
+async fn export(options: &Options) -> Result<()> {
     println!(
         "{} {}Locating existing metadata presets...",
         style("[1/4]").bold().dim(),
@@ -65,48 +532,29 @@ async fn main() -> Result<()> {
         style("[2/4]").bold().dim(),
         DIVING_MASK
     );
-    let connection = macdive::establish_connection(&options.macdive_database()?).await?;
-    let sites = macdive::sites(&connection)
-        .await?
-        .into_iter()
-        .map(|site| site.try_into())
-        .collect::<Result<Vec<types::DiveSite>, ConversionError>>()?;
+    if options.backfill_altitude {
+        backfill_altitude(options).await?;
+    }
+    let sites = fetch_sites(options).await?;
 
     println!(
         "{} {}Looking up addresses for dive sites...",
         style("[3/4]").bold().dim(),
         SATELLITE
     );
-    let mut sites: Vec<types::DiveSite> = sites
+    let overrides = resolve_overrides(options, &sites)?;
+
+    let sites: Vec<types::DiveSite> = sites
         .into_iter()
         .filter(|site| options.force || !existing.contains_key(&site.uuid))
         .collect();
     let pb = ProgressBar::new(sites.len() as u64);
-
-    if let Some(key) = &options.api_key {
-        sites = futures::stream::iter(sites)
-            .map(|site| {
-                pb.inc(1);
-                geocode::geocode_site(site, key)
-            })
-            .buffer_unordered(10usize)
-            .collect::<Vec<_>>()
-            .await
-            .into_iter()
-            .map(|item| {
-                item.map_err(ConversionError::GeocodingError)
-                    .and_then(|site| {
-                        geocode::apply_overrides(site, &options.location_overrides())
-                            .map_err(ConversionError::GeocodingError)
-                    })
-            })
-            .collect::<Result<Vec<_>, ConversionError>>()?;
-    }
+    let (sites, failures) = locate_sites(options, sites, &overrides, &pb).await?;
+    pb.finish_and_clear();
     let presets = sites
         .into_iter()
         .map(|site| site.try_into())
         .collect::<Result<Vec<MetadataPreset>, ConversionError>>()?;
-    pb.finish_and_clear();
 
     println!(
         "{} {}Writing Lightroom Metadata Presets...",
@@ -114,10 +562,44 @@ async fn main() -> Result<()> {
         FILE_FOLDER
     );
     lightroom::write_presets(&options.lightroom_metadata()?, &presets, &existing)?;
+    output::success(format!("Wrote {} metadata presets", presets.len()));
 
-    if !presets.is_empty() {
-        print_summary(&presets);
-    }
+    report_summary(options, &presets)?;
+    report_failures(&failures, options.strict)?;
 
     Ok(())
 }
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::try_parse() {
+        Ok(options) => options,
+        // Help and version output are reported as errors by clap as well
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(ExitCode::Usage.code());
+        }
+    };
+    output::init(options.no_color);
+
+    let result = match &options.command {
+        Some(Command::Check { strict_schema }) => check(&options, *strict_schema).await,
+        Some(Command::Paths) => {
+            paths(&options);
+            Ok(())
+        }
+        Some(Command::ExportOverrides { output }) => export_overrides(&options, output).await,
+        Some(Command::ExportOv2 { output, by_country }) => {
+            export_ov2(&options, output, *by_country).await
+        }
+        Some(Command::SampleDb { output, sites }) => sample_db(output, *sites).await,
+        Some(Command::Preview(preview_options)) => preview(&options, preview_options).await,
+        None => export(&options).await,
+    };
+
+    if let Err(e) = result {
+        output::error(format!("Error: {:?}", e));
+        std::process::exit(ExitCode::from_error(&e).code());
+    }
+}
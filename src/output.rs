@@ -0,0 +1,25 @@
+use console::style;
+use std::fmt::Display;
+
+/// Disable colored output when requested with `--no-color` or the `NO_COLOR` environment variable
+pub fn init(no_color: bool) {
+    if no_color || std::env::var_os("NO_COLOR").is_some() {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+}
+
+/// Report a change that has been applied
+pub fn success(message: impl Display) {
+    println!("{}", style(message).green());
+}
+
+/// Report something that was skipped or needs the user's attention
+pub fn warning(message: impl Display) {
+    eprintln!("{}", style(message).for_stderr().yellow());
+}
+
+/// Report a failure
+pub fn error(message: impl Display) {
+    eprintln!("{}", style(message).for_stderr().red());
+}
@@ -0,0 +1,183 @@
+use crate::macdive::models::DiveSite;
+use crate::types::Coordinates;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// TomTom OV2 record type of a simple point of interest
+const SIMPLE_POI: u8 = 2;
+/// Type byte, record length, longitude and latitude
+const HEADER_LENGTH: usize = 13;
+/// OV2 stores coordinates as integers in 1/100000 of a degree
+const COORDINATE_SCALE: f64 = 100_000.0;
+
+/// Encode a simple POI record, all integers are little endian and the name is NUL terminated
+pub fn encode_record(name: &str, coordinates: Coordinates) -> Vec<u8> {
+    let length = HEADER_LENGTH + name.len() + 1;
+    let mut record = Vec::with_capacity(length);
+
+    record.push(SIMPLE_POI);
+    record.extend_from_slice(&(length as u32).to_le_bytes());
+    record.extend_from_slice(&((coordinates.lon * COORDINATE_SCALE).round() as i32).to_le_bytes());
+    record.extend_from_slice(&((coordinates.lat * COORDINATE_SCALE).round() as i32).to_le_bytes());
+    record.extend_from_slice(name.as_bytes());
+    record.push(0);
+
+    record
+}
+
+/// Encode the dive sites as consecutive records, sites without GPS coordinates are skipped
+fn encode_sites<'a>(sites: impl IntoIterator<Item = &'a DiveSite>) -> Vec<u8> {
+    sites
+        .into_iter()
+        .filter_map(|site| {
+            let name = site.name.as_deref().unwrap_or("");
+            Some(encode_record(name, site.try_coordinates()?))
+        })
+        .flatten()
+        .collect()
+}
+
+/// File name for the dive sites of a country, with surrounding and repeated whitespace removed,
+/// characters most file systems reject replaced and no leading dot to hide the file
+fn country_filename(country: Option<&str>) -> String {
+    let name = country
+        .unwrap_or("")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace(
+            |c: char| std::path::is_separator(c) || c.is_control() || ":*?\"<>|".contains(c),
+            "-",
+        );
+
+    match name.trim_start_matches('.') {
+        "" => "Unknown".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Write all dive sites to a TomTom OV2 file
+pub fn write_ov2(path: &Path, sites: &[DiveSite]) -> std::io::Result<()> {
+    std::fs::write(path, encode_sites(sites))
+}
+
+/// Write one TomTom OV2 file per country into `directory`, returning the number of files
+pub fn write_ov2_by_country(directory: &Path, sites: &[DiveSite]) -> std::io::Result<usize> {
+    let mut countries: BTreeMap<String, Vec<&DiveSite>> = BTreeMap::new();
+    for site in sites {
+        countries
+            .entry(country_filename(site.country.as_deref()))
+            .or_default()
+            .push(site);
+    }
+
+    std::fs::create_dir_all(directory)?;
+    for (filename, sites) in &countries {
+        std::fs::write(
+            directory.join(format!("{}.ov2", filename)),
+            encode_sites(sites.iter().copied()),
+        )?;
+    }
+
+    Ok(countries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use tempfile::TempDir;
+
+    fn decode_record(record: &[u8]) -> (usize, Coordinates, String) {
+        let int =
+            |offset: usize| i32::from_le_bytes(record[offset..offset + 4].try_into().unwrap());
+        let length = u32::from_le_bytes(record[1..5].try_into().unwrap()) as usize;
+        let name = &record[HEADER_LENGTH..length - 1];
+
+        (
+            length,
+            Coordinates {
+                lat: int(9) as f64 / COORDINATE_SCALE,
+                lon: int(5) as f64 / COORDINATE_SCALE,
+            },
+            String::from_utf8(name.to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let record = encode_record(
+            "Palancar Gardens",
+            Coordinates {
+                lat: 20.3236,
+                lon: -87.0261,
+            },
+        );
+        let (length, coordinates, name) = decode_record(&record);
+
+        assert_eq!(SIMPLE_POI, record[0]);
+        assert_eq!(record.len(), length);
+        assert_eq!(Some(&0), record.last());
+        assert_eq!("Palancar Gardens", name);
+        assert!((coordinates.lat - 20.3236).abs() < 1e-9);
+        assert!((coordinates.lon + 87.0261).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_record_rounds_to_precision() {
+        let record = encode_record(
+            "Blue Hole",
+            Coordinates {
+                lat: 17.315_556,
+                lon: -87.534_444,
+            },
+        );
+        let (_, coordinates, _) = decode_record(&record);
+
+        assert!((coordinates.lat - 17.31556).abs() < 1e-9);
+        assert!((coordinates.lon + 87.53444).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_country_filename() {
+        assert_eq!("Mexico", country_filename(Some("  Mexico\n")));
+        assert_eq!(
+            "Papua New Guinea",
+            country_filename(Some("Papua  New\tGuinea"))
+        );
+        assert_eq!("Bonaire-Curacao", country_filename(Some("Bonaire/Curacao")));
+        assert_eq!("Unknown", country_filename(Some("  ")));
+        assert_eq!("Unknown", country_filename(Some("..")));
+        assert_eq!("hidden", country_filename(Some(".hidden")));
+        assert_eq!("Unknown", country_filename(None));
+    }
+
+    #[test]
+    fn test_write_ov2_by_country() {
+        let site = |name: &str, country: Option<&str>, latitude: Option<f32>| DiveSite {
+            name: Some(name.to_string()),
+            country: country.map(str::to_string),
+            latitude,
+            longitude: Some(-87.0261),
+            ..Default::default()
+        };
+        let sites = vec![
+            site("Palancar Gardens", Some("Mexico"), Some(20.3236)),
+            site("Columbia Wall", Some(" Mexico "), Some(20.3078)),
+            site("Unmapped", Some("Mexico"), None),
+            site("Somewhere", None, Some(20.0)),
+        ];
+        let directory = TempDir::new().unwrap();
+
+        assert_eq!(2, write_ov2_by_country(directory.path(), &sites).unwrap());
+
+        let mexico = std::fs::read(directory.path().join("Mexico.ov2")).unwrap();
+        let (length, coordinates, name) = decode_record(&mexico);
+        assert_eq!("Palancar Gardens", name);
+        assert!((coordinates.lat - 20.3236).abs() < 1e-5);
+        let (_, _, name) = decode_record(&mexico[length..]);
+        assert_eq!("Columbia Wall", name);
+        assert!(directory.path().join("Unknown.ov2").exists());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::errors::PreviewError;
+use crate::macdive::models::DiveSite;
+use crate::types::round_coordinate;
+
+use askama::Template;
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::str::FromStr;
+
+static OSM_ATTRIBUTION: &str =
+    "&copy; <a href=\"https://www.openstreetmap.org/copyright\">OpenStreetMap</a> contributors";
+
+/// Highest zoom level of the preview tile layers
+const MAX_ZOOM: u8 = 19;
+
+#[derive(Template)]
+#[template(path = "preview.html")]
+struct PreviewMap {
+    sites: String,
+    tiles: String,
+}
+
+/// Leaflet tile layer options
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TileLayer {
+    pub url: &'static str,
+    pub attribution: String,
+    pub max_zoom: u8,
+}
+
+/// Tile server used for the background of the map preview
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapProvider {
+    OpenStreetMap,
+    Carto,
+    OpenTopoMap,
+    /// Markers on a blank canvas, only Leaflet itself is loaded from the network
+    None,
+}
+
+impl MapProvider {
+    /// Tile layer to load, `None` when no tiles should be loaded
+    pub fn tile_layer(self) -> Option<TileLayer> {
+        match self {
+            MapProvider::OpenStreetMap => Some(TileLayer {
+                url: "https://{s}.tile.openstreetmap.org/{z}/{x}/{y}.png",
+                attribution: OSM_ATTRIBUTION.to_string(),
+                max_zoom: MAX_ZOOM,
+            }),
+            MapProvider::Carto => Some(TileLayer {
+                url: "https://{s}.basemaps.cartocdn.com/light_all/{z}/{x}/{y}{r}.png",
+                attribution: format!(
+                    "{}, &copy; <a href=\"https://carto.com/attributions\">CARTO</a>",
+                    OSM_ATTRIBUTION
+                ),
+                max_zoom: MAX_ZOOM,
+            }),
+            MapProvider::OpenTopoMap => Some(TileLayer {
+                url: "https://{s}.tile.opentopomap.org/{z}/{x}/{y}.png",
+                attribution: format!(
+                    "{}, SRTM | Map style: &copy; <a href=\"https://opentopomap.org\">OpenTopoMap</a> (CC-BY-SA)",
+                    OSM_ATTRIBUTION
+                ),
+                max_zoom: 17,
+            }),
+            MapProvider::None => None,
+        }
+    }
+}
+
+impl FromStr for MapProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "osm" => Ok(MapProvider::OpenStreetMap),
+            "carto" => Ok(MapProvider::Carto),
+            "opentopomap" => Ok(MapProvider::OpenTopoMap),
+            "none" => Ok(MapProvider::None),
+            _ => Err(format!("Unknown map provider `{}`", s)),
+        }
+    }
+}
+
+/// Leaflet zoom level for the map span MacDive stores in degrees, a heuristic since a
+/// zoom level covers 360 / 2^zoom degrees of longitude
+fn map_zoom(zoom: &str) -> Option<u8> {
+    let span = zoom.trim().parse::<f64>().ok().filter(|span| *span > 0.0)?;
+
+    Some((360.0 / span).log2().round().max(0.0).min(MAX_ZOOM as f64) as u8)
+}
+
+/// GeoJSON feature collection with one point per dive site, sites without GPS are skipped
+pub fn sites_geojson(sites: &[DiveSite], precision: u32) -> Value {
+    let features = sites
+        .iter()
+        .filter_map(|site| Some((site, site.try_coordinates()?)))
+        .map(|(site, coordinates)| {
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [
+                        round_coordinate(coordinates.lon, precision),
+                        round_coordinate(coordinates.lat, precision),
+                    ],
+                },
+                "properties": {
+                    "uuid": site.uuid,
+                    "name": site.name,
+                    "country": site.country,
+                    "zoom": site.zoom.as_deref().and_then(map_zoom),
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+pub fn render(
+    sites: &[DiveSite],
+    precision: u32,
+    provider: MapProvider,
+) -> Result<String, PreviewError> {
+    let tiles = json!(provider.tile_layer());
+
+    // Both values are embedded in a script tag, a literal `</` would end it early
+    Ok(PreviewMap {
+        sites: sites_geojson(sites, precision)
+            .to_string()
+            .replace("</", "<\\/"),
+        tiles: tiles.to_string().replace("</", "<\\/"),
+    }
+    .render()?)
+}
+
+pub fn write_preview(
+    path: &Path,
+    sites: &[DiveSite],
+    precision: u32,
+    provider: MapProvider,
+    open: bool,
+) -> Result<(), PreviewError> {
+    std::fs::write(path, render(sites, precision, provider)?)?;
+
+    if open {
+        let url = format!("file://{}", std::fs::canonicalize(path)?.display());
+        webbrowser::open(&url).map_err(PreviewError::Browser)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sites_geojson() {
+        let site = DiveSite {
+            latitude: Some(20.3236),
+            longitude: Some(-87.0261),
+            name: Some("Palancar Gardens".to_string()),
+            zoom: Some("0.05".to_string()),
+            ..Default::default()
+        };
+        let features = &sites_geojson(&[site, DiveSite::default()], 4)["features"];
+        let feature = &features[0];
+
+        assert_eq!(1, features.as_array().unwrap().len());
+        assert_eq!(
+            json!([-87.0261, 20.3236]),
+            feature["geometry"]["coordinates"]
+        );
+        assert_eq!(json!("Palancar Gardens"), feature["properties"]["name"]);
+        assert_eq!(json!(13), feature["properties"]["zoom"]);
+    }
+
+    #[test]
+    fn test_map_zoom() {
+        assert_eq!(Some(13), map_zoom("0.05"));
+        assert_eq!(Some(1), map_zoom("180"));
+        assert_eq!(Some(0), map_zoom("720"));
+        assert_eq!(Some(MAX_ZOOM), map_zoom("0.0000001"));
+        assert_eq!(None, map_zoom("0"));
+        assert_eq!(None, map_zoom("wide"));
+    }
+
+    #[test]
+    fn test_render_map_provider() {
+        let osm = render(&[], 6, MapProvider::OpenStreetMap).unwrap();
+        assert!(osm.contains("tile.openstreetmap.org"));
+        assert!(!osm.contains("</a>"));
+
+        let topo = render(&[], 6, MapProvider::OpenTopoMap).unwrap();
+        assert!(topo.contains("tile.opentopomap.org"));
+        assert!(topo.contains("\"maxZoom\":17"));
+
+        let blank = render(&[], 6, MapProvider::None).unwrap();
+        assert!(blank.contains("var tiles = null;"));
+        assert!(!blank.contains("openstreetmap"));
+    }
+}
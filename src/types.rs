@@ -1,14 +1,15 @@
-use crate::errors::{ConversionError, GeocodingError};
+use crate::errors::{ConversionError, GeocodingError, OverridesError};
 
 use std::cmp::Ordering;
 use std::convert::{TryFrom, TryInto};
 use std::str::FromStr;
 
 use google_maps::LatLng;
-use rust_decimal::{prelude::FromPrimitive, Decimal};
+use rust_decimal::{prelude::FromPrimitive, Decimal, RoundingStrategy};
 use rust_decimal_macros::dec;
-use serde_derive::Deserialize;
-use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
 use uuid::Uuid;
 
 pub trait DecimalToDms {
@@ -53,24 +54,213 @@ impl DecimalToDms for LatLng {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// WGS84 position of a dive site in decimal degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    Latitude,
+    Longitude,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoordinateFormat {
+    /// Signed decimal degrees, e.g. `21.276900`
+    DecimalDegrees,
+    /// Degrees and decimal minutes, e.g. `21°16.614'N`
+    DegreesDecimalMinutes,
+    /// Degrees, minutes and seconds, e.g. `21°16'36.8"N`
+    DegreesMinutesSeconds,
+}
+
+impl FromStr for CoordinateFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dd" => Ok(CoordinateFormat::DecimalDegrees),
+            "ddm" => Ok(CoordinateFormat::DegreesDecimalMinutes),
+            "dms" => Ok(CoordinateFormat::DegreesMinutesSeconds),
+            _ => Err(format!("Unknown coordinate format `{}`", s)),
+        }
+    }
+}
+
+/// Widen a stored coordinate keeping its decimal value, `21.2769f32` becomes `21.2769f64`
+pub fn widen_coordinate(value: f32) -> f64 {
+    value.to_string().parse().unwrap_or_else(|_| value.into())
+}
+
+/// Round a decimal degree value to `precision` decimals for output, halves round to even
+///
+/// Rounding works on the shortest decimal representation of the value rather than its binary
+/// approximation, so `0.1234565` is treated as exactly halfway and rounds to `0.123456`.
+pub fn round_coordinate(value: f64, precision: u32) -> f64 {
+    Decimal::from_str(&value.to_string())
+        .ok()
+        .and_then(|v| {
+            // Decimal::to_f64 is lossy, parsing the rounded digits yields the closest f64
+            v.round_dp_with_strategy(precision, RoundingStrategy::BankersRounding)
+                .to_string()
+                .parse()
+                .ok()
+        })
+        .unwrap_or(value)
+}
+
+pub fn format_coordinate(
+    value: f64,
+    axis: Axis,
+    format: CoordinateFormat,
+    precision: u32,
+) -> String {
+    let hemisphere = match (axis, value < 0.0) {
+        (Axis::Latitude, false) => 'N',
+        (Axis::Latitude, true) => 'S',
+        (Axis::Longitude, false) => 'E',
+        (Axis::Longitude, true) => 'W',
+    };
+    let absolute = value.abs();
+
+    // Round once on the smallest displayed unit so e.g. 59.96" carries over into the minutes
+    match format {
+        CoordinateFormat::DecimalDegrees => format!(
+            "{:.*}",
+            precision as usize,
+            round_coordinate(value, precision)
+        ),
+        CoordinateFormat::DegreesDecimalMinutes => {
+            let thousandths = (absolute * 60_000.0).round() as u64;
+            format!(
+                "{}°{:.3}'{}",
+                thousandths / 60_000,
+                (thousandths % 60_000) as f64 / 1_000.0,
+                hemisphere
+            )
+        }
+        CoordinateFormat::DegreesMinutesSeconds => {
+            let tenths = (absolute * 36_000.0).round() as u64;
+            format!(
+                r#"{}°{}'{:.1}"{}"#,
+                tenths / 36_000,
+                (tenths % 36_000) / 600,
+                (tenths % 600) as f64 / 10.0,
+                hemisphere
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Overrides {
-    pub locations: HashMap<String, LocationOverride>,
+    pub locations: BTreeMap<String, LocationOverride>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverridesFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl OverridesFormat {
+    /// Detect the format of an overrides file from its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "toml" => Some(OverridesFormat::Toml),
+            "json" => Some(OverridesFormat::Json),
+            "yaml" | "yml" => Some(OverridesFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+impl Overrides {
+    pub fn parse(content: &str, format: OverridesFormat) -> Result<Self, OverridesError> {
+        Ok(match format {
+            OverridesFormat::Toml => toml::from_str(content)?,
+            OverridesFormat::Json => serde_json::from_str(content)?,
+            OverridesFormat::Yaml => serde_yaml::from_str(content)?,
+        })
+    }
+
+    pub fn render(&self, format: OverridesFormat) -> Result<String, OverridesError> {
+        Ok(match format {
+            OverridesFormat::Toml => toml::to_string(self)?,
+            OverridesFormat::Json => serde_json::to_string_pretty(self)?,
+            OverridesFormat::Yaml => serde_yaml::to_string(self)?,
+        })
+    }
+
+    /// Combine two sets of overrides, entries of `other` win on key conflicts
+    pub fn merge(mut self, other: Overrides) -> Overrides {
+        self.locations.extend(other.locations);
+        self
+    }
+}
+
+impl From<&[DiveSite]> for Overrides {
+    /// Snapshot the location of dive sites as overrides keyed by their UUID
+    fn from(sites: &[DiveSite]) -> Self {
+        Self {
+            locations: sites
+                .iter()
+                .map(|site| (site.uuid.to_string(), site.into()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LocationOverride {
+    /// Unique identifier of a single dive site this override applies to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<Uuid>,
+    /// Name of a single dive site this override applies to, compared ignoring case and spacing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Polygon of (longitude, latitude) points, the override applies to all dive sites inside
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub area: Vec<(f32, f32)>,
     /// The full name should be expressed as a verbal name and not as a code
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub country: Option<String>,
     /// ISO country code of the location where the image was created
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub iso_country_code: Option<String>,
     /// The name of the subregion of a country, either a State or Province where the image was created
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub state: Option<String>,
     /// The name of the sub-subregion of a country, could be a county or region name where the image was created
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub region: Option<String>,
     /// The name of the city or area
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub locality: Option<String>,
+    /// The name of the body of water
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_of_water: Option<String>,
+}
+
+impl From<&DiveSite> for LocationOverride {
+    fn from(site: &DiveSite) -> Self {
+        Self {
+            uuid: Some(site.uuid),
+            name: None,
+            area: Vec::new(),
+            country: Some(site.country.clone()),
+            iso_country_code: Some(site.iso_country_code.clone()),
+            state: site.state.clone(),
+            region: site.region.clone(),
+            locality: site.locality.clone(),
+            body_of_water: site.body_of_water.clone(),
+        }
+    }
 }
 
 impl LocationOverride {
@@ -120,6 +310,27 @@ pub struct DiveSite {
     pub site_id: i64,
 }
 
+#[cfg(test)]
+impl DiveSite {
+    /// Dive site with a fixed UUID, tests adjust the fields they need with struct update syntax
+    pub fn sample(name: &str, latitude: f32, longitude: f32) -> Self {
+        DiveSite {
+            uuid: Uuid::parse_str("0b4c9a44-5e3f-4a0e-9a43-2b1f5a3b6c7d").unwrap(),
+            country: "United States".to_string(),
+            iso_country_code: "US".to_string(),
+            state: None,
+            region: None,
+            locality: None,
+            name: name.to_string(),
+            latitude,
+            longitude,
+            altitude: 0.0,
+            body_of_water: None,
+            site_id: 1,
+        }
+    }
+}
+
 impl TryFrom<DiveSite> for LatLng {
     type Error = GeocodingError;
 
@@ -158,7 +369,7 @@ impl TryInto<DiveSite> for crate::macdive::models::DiveSite {
             latitude: self.latitude.ok_or(ConversionError::MissingLatitude)?,
             longitude: self.longitude.ok_or(ConversionError::MissingLongitude)?,
             altitude: 0.0,
-            body_of_water: None,
+            body_of_water: self.body_of_water.filter(|v| !v.is_empty()),
             site_id: self.id,
         })
     }
@@ -168,6 +379,25 @@ impl TryInto<DiveSite> for crate::macdive::models::DiveSite {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_convert_macdive_site() {
+        let site = crate::macdive::models::DiveSite {
+            id: 7,
+            latitude: Some(20.3236),
+            longitude: Some(-87.0261),
+            body_of_water: Some("Caribbean Sea".to_string()),
+            country: Some("Mexico".to_string()),
+            name: Some("Palancar Gardens".to_string()),
+            uuid: Some("0B4C9A44-5E3F-4A0E-9A43-2B1F5A3B6C7D".to_string()),
+            ..Default::default()
+        };
+        let converted: DiveSite = site.try_into().unwrap();
+
+        assert_eq!(Some("Caribbean Sea".to_string()), converted.body_of_water);
+        assert_eq!("MX", converted.iso_country_code);
+        assert_eq!(7, converted.site_id);
+    }
+
     #[test]
     fn test_dms_null_island() {
         // Null Island, Intersection of Prime Meridian and Equator
@@ -231,4 +461,292 @@ mod tests {
             latlng.to_dms().unwrap()
         );
     }
+
+    #[test]
+    fn test_format_coordinate_dd() {
+        assert_eq!(
+            "21.276900",
+            format_coordinate(21.2769, Axis::Latitude, CoordinateFormat::DecimalDegrees, 6)
+        );
+        assert_eq!(
+            "-157.825000",
+            format_coordinate(
+                -157.825,
+                Axis::Longitude,
+                CoordinateFormat::DecimalDegrees,
+                6
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_coordinate_ddm() {
+        assert_eq!(
+            "21°16.614'N",
+            format_coordinate(
+                21.2769,
+                Axis::Latitude,
+                CoordinateFormat::DegreesDecimalMinutes,
+                6
+            )
+        );
+        assert_eq!(
+            "157°49.500'W",
+            format_coordinate(
+                -157.825,
+                Axis::Longitude,
+                CoordinateFormat::DegreesDecimalMinutes,
+                6
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_coordinate_dms() {
+        assert_eq!(
+            "21°16'36.8\"N",
+            format_coordinate(
+                21.2769,
+                Axis::Latitude,
+                CoordinateFormat::DegreesMinutesSeconds,
+                6
+            )
+        );
+        assert_eq!(
+            "33°51'22.2\"S",
+            format_coordinate(
+                -33.856159,
+                Axis::Latitude,
+                CoordinateFormat::DegreesMinutesSeconds,
+                6
+            )
+        );
+        assert_eq!(
+            "151°12'54.9\"E",
+            format_coordinate(
+                151.215256,
+                Axis::Longitude,
+                CoordinateFormat::DegreesMinutesSeconds,
+                6
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_coordinate_carry() {
+        // 10°59'59.99" rounds up to a full degree instead of showing 60 seconds
+        assert_eq!(
+            "11°0'0.0\"N",
+            format_coordinate(
+                10.999_997,
+                Axis::Latitude,
+                CoordinateFormat::DegreesMinutesSeconds,
+                6
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_coordinate_dd_precision() {
+        assert_eq!(
+            "21.28",
+            format_coordinate(21.2769, Axis::Latitude, CoordinateFormat::DecimalDegrees, 2)
+        );
+        assert_eq!(
+            "-158",
+            format_coordinate(
+                -157.825,
+                Axis::Longitude,
+                CoordinateFormat::DecimalDegrees,
+                0
+            )
+        );
+    }
+
+    #[test]
+    fn test_round_coordinate_half_to_even() {
+        assert_eq!(0.123456, round_coordinate(0.1234565, 6));
+        assert_eq!(0.123458, round_coordinate(0.1234575, 6));
+        assert_eq!(-0.123456, round_coordinate(-0.1234565, 6));
+        assert_eq!(2.0, round_coordinate(2.5, 0));
+        assert_eq!(4.0, round_coordinate(3.5, 0));
+    }
+
+    #[test]
+    fn test_round_coordinate_without_float_noise() {
+        assert_eq!(-158.75882, round_coordinate(-158.75882, 6));
+        assert_eq!("-158.75882", round_coordinate(-158.75882, 6).to_string());
+    }
+
+    #[test]
+    fn test_round_coordinate_keeps_shorter_values() {
+        assert_eq!(21.2769, round_coordinate(21.2769, 6));
+        assert_eq!(-180.0, round_coordinate(-180.0, 6));
+    }
+
+    #[test]
+    fn test_widen_coordinate() {
+        assert_eq!(21.2769, widen_coordinate(21.2769));
+        assert_eq!(-157.825, widen_coordinate(-157.825));
+    }
+
+    #[test]
+    fn test_overrides_snapshot_round_trip() {
+        let site = DiveSite {
+            country: "Mexico".to_string(),
+            iso_country_code: "MX".to_string(),
+            state: Some("Quintana Roo".to_string()),
+            region: Some("Cozumel".to_string()),
+            body_of_water: Some("Caribbean Sea".to_string()),
+            ..DiveSite::sample("Palancar Gardens", 20.3236, -87.0261)
+        };
+
+        for format in &[
+            OverridesFormat::Toml,
+            OverridesFormat::Json,
+            OverridesFormat::Yaml,
+        ] {
+            let snapshot = Overrides::from(vec![site.clone()].as_slice())
+                .render(*format)
+                .unwrap();
+            let overrides = Overrides::parse(&snapshot, *format).unwrap();
+            let location = &overrides.locations[&site.uuid.to_string()];
+
+            assert_eq!(Some(site.uuid), location.uuid);
+            assert_eq!(Some("MX".to_string()), location.iso_country_code);
+            assert_eq!(Some("Cozumel".to_string()), location.region);
+            assert_eq!(None, location.locality);
+            assert!(location.area.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_overrides_area_round_trip() {
+        let mut original = overrides(&[("anacapa", "Anacapa Island")]);
+        original.locations.get_mut("anacapa").unwrap().area =
+            vec![(-119.4567, 34.0314), (-119.3358, 33.9935)];
+
+        for format in &[
+            OverridesFormat::Toml,
+            OverridesFormat::Json,
+            OverridesFormat::Yaml,
+        ] {
+            let parsed = Overrides::parse(&original.render(*format).unwrap(), *format).unwrap();
+
+            assert_eq!(
+                original.locations["anacapa"].area,
+                parsed.locations["anacapa"].area
+            );
+            assert_eq!(
+                Some("Anacapa Island".to_string()),
+                locality(&parsed, "anacapa")
+            );
+        }
+    }
+
+    #[test]
+    fn test_overrides_format_from_path() {
+        assert_eq!(
+            Some(OverridesFormat::Toml),
+            OverridesFormat::from_path(Path::new("examples/locations.toml"))
+        );
+        assert_eq!(
+            Some(OverridesFormat::Json),
+            OverridesFormat::from_path(Path::new("locations.JSON"))
+        );
+        assert_eq!(
+            Some(OverridesFormat::Yaml),
+            OverridesFormat::from_path(Path::new("locations.yml"))
+        );
+        assert_eq!(None, OverridesFormat::from_path(Path::new("locations")));
+        assert_eq!(None, OverridesFormat::from_path(Path::new("locations.txt")));
+    }
+
+    #[test]
+    fn test_parse_example_overrides() {
+        let overrides = Overrides::parse(
+            include_str!("../examples/locations.toml"),
+            OverridesFormat::Toml,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some("Cozumel".to_string()),
+            overrides.locations["cozumel"].region
+        );
+    }
+
+    fn overrides(entries: &[(&str, &str)]) -> Overrides {
+        Overrides {
+            locations: entries
+                .iter()
+                .map(|(key, locality)| {
+                    (
+                        key.to_string(),
+                        LocationOverride {
+                            uuid: None,
+                            name: None,
+                            area: vec![],
+                            country: None,
+                            iso_country_code: None,
+                            state: None,
+                            region: None,
+                            locality: Some(locality.to_string()),
+                            body_of_water: None,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn locality(overrides: &Overrides, key: &str) -> Option<String> {
+        overrides.locations[key].locality.clone()
+    }
+
+    #[test]
+    fn test_merge_overrides_disjoint() {
+        let merged = overrides(&[("cozumel", "San Miguel")])
+            .merge(overrides(&[("anacapa", "Anacapa Island")]));
+
+        assert_eq!(2, merged.locations.len());
+        assert_eq!(Some("San Miguel".to_string()), locality(&merged, "cozumel"));
+        assert_eq!(
+            Some("Anacapa Island".to_string()),
+            locality(&merged, "anacapa")
+        );
+    }
+
+    #[test]
+    fn test_merge_overrides_overlapping() {
+        let merged = overrides(&[("cozumel", "San Miguel"), ("anacapa", "Anacapa")])
+            .merge(overrides(&[("cozumel", "Cozumel")]));
+
+        assert_eq!(2, merged.locations.len());
+        assert_eq!(Some("Cozumel".to_string()), locality(&merged, "cozumel"));
+        assert_eq!(Some("Anacapa".to_string()), locality(&merged, "anacapa"));
+    }
+
+    #[test]
+    fn test_merge_overrides_empty() {
+        let merged = Overrides::default().merge(overrides(&[("cozumel", "San Miguel")]));
+        assert_eq!(Some("San Miguel".to_string()), locality(&merged, "cozumel"));
+
+        let merged = overrides(&[("cozumel", "San Miguel")]).merge(Overrides::default());
+        assert_eq!(Some("San Miguel".to_string()), locality(&merged, "cozumel"));
+
+        assert!(Overrides::default()
+            .merge(Overrides::default())
+            .locations
+            .is_empty());
+    }
+
+    #[test]
+    fn test_coordinate_format_from_str() {
+        assert_eq!(
+            Ok(CoordinateFormat::DegreesDecimalMinutes),
+            "DDM".parse::<CoordinateFormat>()
+        );
+        assert!("utm".parse::<CoordinateFormat>().is_err());
+    }
 }